@@ -1,4 +1,21 @@
-use crate::{debug::disassembly_instruction, value::Value};
+mod serialize;
+
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    mem::size_of,
+};
+
+use crate::{
+    debug::disassembly_instruction,
+    memory::{Gc, Heap},
+    object::{ObjFunction, ObjString},
+    scanner::Span,
+    value::{Unpacked, Value},
+};
+
+pub use self::serialize::{BytecodeError, BYTECODE_VERSION, MAGIC};
 
 macro_rules! opcode {
     ( $(#[$attr:meta])* $vis:vis enum $name:ident : $type:ty {
@@ -35,6 +52,7 @@ opcode! {
     pub enum Opcode: u8 {
         Return,
         Constant,
+        ConstantLong,
         Nil,
         True,
         False,
@@ -42,9 +60,20 @@ opcode! {
         Dup,
         GetLocal,
         GetGlobal,
+        GetGlobalLong,
         DefineGlobal,
+        DefineGlobalLong,
         SetLocal,
         SetGlobal,
+        SetGlobalLong,
+        GetUpvalue,
+        SetUpvalue,
+        GetProperty,
+        GetPropertyLong,
+        SetProperty,
+        SetPropertyLong,
+        GetSuper,
+        GetSuperLong,
         Equal,
         Greater,
         Less,
@@ -58,22 +87,85 @@ opcode! {
         Jump,
         JumpIfFalse,
         Loop,
+        Call,
+        Invoke,
+        InvokeLong,
+        SuperInvoke,
+        SuperInvokeLong,
+        Closure,
+        ClosureLong,
+        CloseUpvalue,
+        Class,
+        ClassLong,
+        Inherit,
+        Method,
+        MethodLong,
+    }
+}
+
+impl Opcode {
+    /// Whether it's the form of an instruction taking a 24-bit constant
+    /// operand, for chunks with more than 256 constants.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            Opcode::ConstantLong
+                | Opcode::GetGlobalLong
+                | Opcode::DefineGlobalLong
+                | Opcode::SetGlobalLong
+                | Opcode::GetPropertyLong
+                | Opcode::SetPropertyLong
+                | Opcode::GetSuperLong
+                | Opcode::InvokeLong
+                | Opcode::SuperInvokeLong
+                | Opcode::ClosureLong
+                | Opcode::ClassLong
+                | Opcode::MethodLong
+        )
     }
 }
 
 #[derive(Default)]
 pub struct Chunk {
     code: Vec<u8>,
-    lines: Vec<u32>,
+    // (offset of first byte, line) for each run of bytes on the same line
+    lines: Vec<(usize, u32)>,
+    // same, but for spans, delta encoded as they'd otherwise take more
+    // room than the code itself, see `SpanRuns`
+    spans: Vec<u8>,
+    // start of the last run and its span, what the next one is encoded
+    // relative to
+    last_span: Option<(usize, Span)>,
     constants: Vec<Value>,
+    // index of the first constant for each key, so finding one to reuse
+    // doesn't have to scan the whole pool
+    constant_ids: HashMap<ConstantKey, Id>,
 }
 
-pub type Id = u8;
+/// Index into chunk's constants, only ever up to 24 bits wide.
+pub type Id = u32;
+
+pub const MAX_CONSTANTS: usize = 1 << 24;
 
 impl Chunk {
-    pub fn write_byte(&mut self, byte: u8, line: u32) {
+    pub fn write_byte(&mut self, byte: u8, line: u32, span: Span) {
+        if self.lines.last().map(|&(_, last)| last) != Some(line) {
+            self.lines.push((self.code.len(), line));
+        }
+        if self.last_span.map(|(_, last)| last) != Some(span) {
+            let (last_offset, last) = self.last_span.unwrap_or_default();
+            let offset = self.code.len();
+            write_varint(&mut self.spans, (offset - last_offset) as u64);
+            let start = span.start as i64 - last.start as i64;
+            // zigzag, so that small negative deltas stay short too
+            write_varint(
+                &mut self.spans,
+                ((start << 1) ^ (start >> 63)) as u64,
+            );
+            write_varint(&mut self.spans, (span.end - span.start) as u64);
+            self.last_span = Some((offset, span));
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
     pub fn code(&self) -> &[u8] {
@@ -85,48 +177,420 @@ impl Chunk {
     }
 
     pub fn find_constant(&self, value: &Value) -> Option<Id> {
-        self.constants
-            .iter()
-            .position(|x| x == value)
-            .map(|id| id.try_into().unwrap())
+        self.constant_ids.get(&ConstantKey::new(*value)?).copied()
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
     }
 
-    pub fn constants_len(&self) -> u8 {
-        self.constants.len().try_into().unwrap()
+    pub fn constants_len(&self) -> usize {
+        self.constants.len()
     }
 
-    pub fn disassembly(&self, name: &str) {
-        println!("== {name} ==");
+    /// Writes out every instruction, if `source` is given, then also
+    /// the source code each group of instructions was compiled from.
+    pub fn disassembly(
+        &self,
+        name: &str,
+        source: Option<&str>,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        writeln!(out, "== {name} ==")?;
 
+        let mut runs = self.span_runs().peekable();
+        let mut span = None;
+        let mut last_span = None;
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = disassembly_instruction(&self, offset);
+            while let Some((_, next)) =
+                runs.next_if(|&(start, _)| start <= offset)
+            {
+                span = Some(next);
+            }
+            let source = source.filter(|_| last_span != span);
+            if let (Some(source), Some(span)) = (source, span) {
+                let text = source.get(span.start..span.end).unwrap_or("");
+                let text = text.lines().next().unwrap_or("").trim();
+                writeln!(out, "{:11}; {text}", "")?;
+            }
+            last_span = span;
+            offset = disassembly_instruction(self, offset, out)?;
         }
+        Ok(())
     }
 
+    /// Caller has to keep the pool under [`MAX_CONSTANTS`].
     pub fn add_constant(&mut self, value: Value) -> Id {
         let id = self.constants.len();
+        debug_assert!(id < MAX_CONSTANTS);
         self.constants.push(value);
-        id.try_into().unwrap()
+        if let Some(key) = ConstantKey::new(value) {
+            self.constant_ids.entry(key).or_insert(id as Id);
+        }
+        id as Id
     }
 
     pub fn get_constant(&self, id: Id) -> &Value {
-        &self.constants[usize::from(id)]
+        &self.constants[id as usize]
     }
 
     pub fn get_line(&self, offset: usize) -> u32 {
-        self.lines[offset]
+        self.lines[self.line_run(offset)].1
     }
 
+    // `None` if byte before `offset` is on the same line
     pub fn get_line_if_first(&self, offset: usize) -> Option<u32> {
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            None
-        } else {
-            Some(self.lines[offset])
+        let (start, line) = self.lines[self.line_run(offset)];
+        (start == offset).then_some(line)
+    }
+
+    fn line_run(&self, offset: usize) -> usize {
+        assert!(offset < self.code.len(), "offset out of bounds");
+        self.lines.partition_point(|&(start, _)| start <= offset) - 1
+    }
+
+    /// `None` for chunks without spans, like deserialized ones.
+    ///
+    /// Decodes spans from the start of the chunk, so it's meant for
+    /// reporting errors, not for walking the whole chunk.
+    pub fn get_span(&self, offset: usize) -> Option<Span> {
+        self.span_runs()
+            .take_while(|&(start, _)| start <= offset)
+            .last()
+            .map(|(_, span)| span)
+    }
+
+    /// Span of operand of the instruction at `offset`, `n`th counting back
+    /// from the last one, e.g. for `a + b`, 0 gives `b` and 1 gives `a`.
+    ///
+    /// Relies on the last instruction of each expression spanning all of it,
+    /// so going back from the instruction, each operand is the first span
+    /// that ends before the previous operand starts. Short-circuiting
+    /// `and`/`or` don't end with their own instruction, so operands using
+    /// them resolve to their right-hand side.
+    pub fn operand_span(&self, offset: usize, n: usize) -> Option<Span> {
+        let runs: Vec<_> = self
+            .span_runs()
+            .take_while(|&(start, _)| start <= offset)
+            .collect();
+        let (&(_, outer), before) = runs.split_last()?;
+        let mut limit = outer.end;
+        let mut remaining = n;
+        for &(_, span) in before.iter().rev() {
+            if span.start < outer.start || span.end > outer.end {
+                return None;
+            }
+            if span.end <= limit {
+                if remaining == 0 {
+                    return Some(span);
+                }
+                remaining -= 1;
+                limit = span.start;
+            }
+        }
+        None
+    }
+
+    /// Bytes taken by code, constants and debug info, all of which live
+    /// outside of the chunk itself.
+    pub fn allocated_size(&self) -> usize {
+        self.code.capacity()
+            + self.lines.capacity() * size_of::<(usize, u32)>()
+            + self.spans.capacity()
+            + self.constants.capacity() * size_of::<Value>()
+            + self.constant_ids.capacity() * size_of::<(ConstantKey, Id)>()
+    }
+
+    /// Copy of the chunk with its strings and functions allocated in
+    /// `heap` instead, to run it in another vm. Like [`Heap::alloc`], it
+    /// never collects.
+    pub fn copy_to(&self, heap: &mut Heap) -> Chunk {
+        let mut chunk = Chunk {
+            code: self.code.clone(),
+            lines: self.lines.clone(),
+            spans: self.spans.clone(),
+            last_span: self.last_span,
+            ..Chunk::default()
+        };
+        for &constant in &self.constants {
+            let constant = match constant.unpack() {
+                Unpacked::String(s) => {
+                    Value::from(heap.intern(s.as_str().to_owned()))
+                }
+                Unpacked::Function(function) => {
+                    let chunk = function.chunk.copy_to(heap);
+                    Value::from(heap.alloc(ObjFunction {
+                        arity: function.arity,
+                        upvalue_count: function.upvalue_count,
+                        chunk,
+                        name: function.name.clone(),
+                    }))
+                }
+                _ => constant,
+            };
+            chunk.add_constant(constant);
         }
+        chunk
+    }
+
+    fn span_runs(&self) -> SpanRuns<'_> {
+        SpanRuns {
+            bytes: &self.spans,
+            offset: 0,
+            span: Span::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+}
+
+// decodes `Chunk::spans`: each run is the distance from the previous run's
+// first byte, the zigzag encoded difference from its span's start, and
+// the length of its own span, all as LEB128 varints
+struct SpanRuns<'c> {
+    bytes: &'c [u8],
+    offset: usize,
+    span: Span,
+}
+
+impl Iterator for SpanRuns<'_> {
+    type Item = (usize, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        self.offset += self.read_varint() as usize;
+        let start = self.read_varint();
+        let start = ((start >> 1) as i64) ^ -((start & 1) as i64);
+        let start = (self.span.start as i64 + start) as usize;
+        let len = self.read_varint() as usize;
+        self.span = Span {
+            start,
+            end: start + len,
+        };
+        Some((self.offset, self.span))
+    }
+}
+
+impl SpanRuns<'_> {
+    fn read_varint(&mut self) -> u64 {
+        let mut value = 0;
+        for (i, &byte) in self.bytes.iter().enumerate() {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.bytes = &self.bytes[i + 1..];
+                return value;
+            }
+        }
+        unreachable!("spans end in the middle of a varint")
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// constants that compare equal share a key, so they get deduplicated the
+// same way `==` would: nan never equals anything and `-0.0` equals `0.0`
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    // interned, so pointers are equal exactly when contents are
+    String(Gc<ObjString>),
+}
+
+impl ConstantKey {
+    // `None` for values that never equal an existing constant: nan, and
+    // objects other than strings, as each function is allocated anew
+    fn new(value: Value) -> Option<Self> {
+        match value.unpack() {
+            Unpacked::Nil => Some(Self::Nil),
+            Unpacked::Bool(b) => Some(Self::Bool(b)),
+            Unpacked::Number(n) if n.is_nan() => None,
+            // also matches `-0.0`
+            Unpacked::Number(0.0) => Some(Self::Number(0)),
+            Unpacked::Number(n) => Some(Self::Number(n.to_bits())),
+            Unpacked::String(s) => Some(Self::String(s)),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for ConstantKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::String(a), Self::String(b)) => Gc::ptr_eq(*a, *b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ConstantKey {}
+
+impl Hash for ConstantKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Nil => {}
+            Self::Bool(b) => b.hash(state),
+            Self::Number(bits) => bits.hash(state),
+            Self::String(s) => s.hash().hash(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Heap;
+
+    fn chunk_with_lines(lines: &[u32]) -> Chunk {
+        let mut chunk = Chunk::default();
+        for &line in lines {
+            chunk.write_byte(Opcode::Nil.as_u8(), line, Span::default());
+        }
+        chunk
+    }
+
+    #[test]
+    fn one_run_per_line_change() {
+        let chunk = chunk_with_lines(&[1, 1, 2, 2, 2, 5, 1, 1]);
+        assert_eq!(chunk.lines, [(0, 1), (2, 2), (5, 5), (6, 1)]);
+    }
+
+    #[test]
+    fn get_line_at_run_boundaries() {
+        let lines = [1, 1, 2, 2, 2, 5, 1, 1];
+        let chunk = chunk_with_lines(&lines);
+        for (offset, &line) in lines.iter().enumerate() {
+            assert_eq!(chunk.get_line(offset), line, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn get_line_if_first_at_run_boundaries() {
+        let chunk = chunk_with_lines(&[1, 1, 2, 2, 2, 5, 1, 1]);
+        let firsts: Vec<_> = (0..chunk.code().len())
+            .map(|offset| chunk.get_line_if_first(offset))
+            .collect();
+        assert_eq!(
+            firsts,
+            [Some(1), None, Some(2), None, None, Some(5), Some(1), None]
+        );
+    }
+
+    #[test]
+    fn single_byte_runs() {
+        let lines = [3, 4, 3, 4];
+        let chunk = chunk_with_lines(&lines);
+        for (offset, &line) in lines.iter().enumerate() {
+            assert_eq!(chunk.get_line(offset), line);
+            assert_eq!(chunk.get_line_if_first(offset), Some(line));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "offset out of bounds")]
+    fn get_line_past_end() {
+        let chunk = chunk_with_lines(&[1, 2]);
+        chunk.get_line(2);
+    }
+
+    #[test]
+    fn spans_survive_encoding() {
+        let spans = [
+            Span { start: 0, end: 3 },
+            Span { start: 0, end: 3 },
+            Span {
+                start: 10,
+                end: 200,
+            },
+            Span { start: 4, end: 5 },
+            Span {
+                start: 100_000,
+                end: 100_000,
+            },
+            Span { start: 1, end: 2 },
+        ];
+        let mut chunk = Chunk::default();
+        for span in spans {
+            chunk.write_byte(Opcode::Nil.as_u8(), 1, span);
+        }
+        for (offset, &span) in spans.iter().enumerate() {
+            assert_eq!(chunk.get_span(offset), Some(span), "offset {offset}");
+        }
+        assert_eq!(chunk.span_runs().count(), 5);
+    }
+
+    #[test]
+    fn numbers_dedup_by_value() {
+        let mut chunk = Chunk::default();
+        let one = chunk.add_constant(Value::from(1.0));
+        let two = chunk.add_constant(Value::from(2.0));
+        assert_eq!(chunk.find_constant(&Value::from(1.0)), Some(one));
+        assert_eq!(chunk.find_constant(&Value::from(2.0)), Some(two));
+        assert_eq!(chunk.find_constant(&Value::from(3.0)), None);
+    }
+
+    #[test]
+    fn nan_never_dedups() {
+        let mut chunk = Chunk::default();
+        chunk.add_constant(Value::from(f64::NAN));
+        assert_eq!(chunk.find_constant(&Value::from(f64::NAN)), None);
+    }
+
+    #[test]
+    fn negative_zero_dedups_with_zero() {
+        let mut chunk = Chunk::default();
+        let zero = chunk.add_constant(Value::from(0.0));
+        assert_eq!(chunk.find_constant(&Value::from(-0.0)), Some(zero));
+
+        let mut chunk = Chunk::default();
+        let zero = chunk.add_constant(Value::from(-0.0));
+        assert_eq!(chunk.find_constant(&Value::from(0.0)), Some(zero));
+    }
+
+    #[test]
+    fn first_of_equal_constants_is_found() {
+        let mut chunk = Chunk::default();
+        let first = chunk.add_constant(Value::from(0.0));
+        chunk.add_constant(Value::from(-0.0));
+        assert_eq!(chunk.find_constant(&Value::from(-0.0)), Some(first));
+    }
+
+    #[test]
+    fn strings_dedup_by_contents() {
+        let mut heap = Heap::default();
+        let mut chunk = Chunk::default();
+        let a = chunk.add_constant(Value::from(heap.intern("a".into())));
+        chunk.add_constant(Value::from(heap.intern("b".into())));
+        let a_again = Value::from(heap.intern(String::from("a")));
+        assert_eq!(chunk.find_constant(&a_again), Some(a));
+        let c = Value::from(heap.intern("c".into()));
+        assert_eq!(chunk.find_constant(&c), None);
     }
 
-    pub fn len(&self) -> u16 {
-        self.code.len().try_into().unwrap()
+    #[test]
+    fn strings_and_numbers_dont_mix() {
+        let mut heap = Heap::default();
+        let mut chunk = Chunk::default();
+        chunk.add_constant(Value::from(heap.intern("1".into())));
+        assert_eq!(chunk.find_constant(&Value::from(1.0)), None);
     }
 }
@@ -0,0 +1,443 @@
+//! Binary format of compiled scripts, what `.loxc` files contain.
+
+use std::fmt;
+
+use super::{Chunk, MAX_CONSTANTS};
+use crate::{
+    memory::{Gc, Heap},
+    object::ObjFunction,
+    value::{Unpacked, Value},
+};
+
+/// First bytes of every serialized chunk. The first one never occurs in
+/// UTF-8, so no source code can be mistaken for bytecode.
+pub const MAGIC: &[u8; 5] = b"\xffloxc";
+
+/// Version of the opcode set, bumped whenever opcodes or their operands
+/// change, so that stale files get rejected instead of misread.
+pub const BYTECODE_VERSION: u16 = 2;
+
+// deeper nesting than that is more likely a crafted file than a script,
+// and would overflow the stack while reading it
+const MAX_NESTING: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    /// Doesn't start with [`MAGIC`], so likely isn't bytecode at all.
+    NotBytecode,
+    /// Written by a clox with different opcode set.
+    Version(u16),
+    /// Ends in the middle of something.
+    Truncated,
+    /// Read up to `offset` fine, but what's there makes no sense.
+    Malformed { offset: usize, message: String },
+    /// Read fine, but instruction at `offset` could misbehave when run,
+    /// e.g. jump outside of its chunk or pop off an empty stack.
+    Invalid {
+        /// `None` for top-level script.
+        function: Option<String>,
+        offset: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotBytecode => write!(f, "Not a compiled lox script."),
+            Self::Version(version) => write!(
+                f,
+                "Compiled for bytecode version {version}, \
+                 but this clox runs version {BYTECODE_VERSION}."
+            ),
+            Self::Truncated => write!(f, "Compiled script is truncated."),
+            Self::Malformed { offset, message } => {
+                write!(f, "Malformed bytecode at byte {offset}: {message}.")
+            }
+            Self::Invalid {
+                function,
+                offset,
+                message,
+            } => {
+                write!(f, "Invalid bytecode at {offset:04} in ")?;
+                match function {
+                    Some(name) => write!(f, "{name}(): {message}."),
+                    None => write!(f, "script: {message}."),
+                }
+            }
+        }
+    }
+}
+
+impl Chunk {
+    /// Encodes the chunk, along with functions in its constants.
+    ///
+    /// Integers are little-endian, and counts and lengths are `u32`:
+    ///
+    /// ```text
+    /// file     = MAGIC version:u16 chunk
+    /// chunk    = count constant*  length code:u8*  count run*
+    /// run      = offset:u32 line:u32
+    /// constant = 0 | 1 | 2                        nil, false, true
+    ///          | 3 bits:u64                       number
+    ///          | 4 string
+    ///          | 5 arity:u8 upvalues:u32 name chunk
+    /// name     = 0 | 1 string                     `None` or `Some`
+    /// string   = length utf8:u8*
+    /// ```
+    ///
+    /// Runs of the line table are the same as [`Chunk`] keeps. Spans aren't
+    /// stored, as they're useless without the source, so runtime errors
+    /// of deserialized chunks come without excerpts.
+    ///
+    /// # Panics
+    ///
+    /// If a constant is an object that compiler never puts in a chunk,
+    /// like a closure or an instance.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(BYTECODE_VERSION.to_le_bytes());
+        write_chunk(self, &mut out);
+        out
+    }
+
+    /// Decodes chunk written by [`Chunk::serialize`], allocating its
+    /// strings and functions in `heap`. Like [`Heap::alloc`], it never
+    /// collects.
+    ///
+    /// Only the format gets checked, not the code itself,
+    /// see [`verify`](crate::verify::verify) for that.
+    pub fn deserialize(
+        bytes: &[u8],
+        heap: &mut Heap,
+    ) -> Result<Self, BytecodeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(BytecodeError::NotBytecode);
+        }
+        let mut reader = Reader {
+            bytes,
+            offset: MAGIC.len(),
+            heap,
+            depth: 0,
+        };
+        let version = reader.u16()?;
+        if version != BYTECODE_VERSION {
+            return Err(BytecodeError::Version(version));
+        }
+        let chunk = reader.chunk()?;
+        if reader.offset != bytes.len() {
+            return Err(reader.malformed("trailing bytes after the script"));
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_u32(n: usize, out: &mut Vec<u8>) {
+    let n = u32::try_from(n).expect("too big to serialize");
+    out.extend(n.to_le_bytes());
+}
+
+fn write_string(s: &str, out: &mut Vec<u8>) {
+    write_u32(s.len(), out);
+    out.extend(s.as_bytes());
+}
+
+fn write_chunk(chunk: &Chunk, out: &mut Vec<u8>) {
+    write_u32(chunk.constants.len(), out);
+    for &constant in &chunk.constants {
+        write_constant(constant, out);
+    }
+    write_u32(chunk.code.len(), out);
+    out.extend(&chunk.code);
+    write_u32(chunk.lines.len(), out);
+    for &(offset, line) in &chunk.lines {
+        write_u32(offset, out);
+        out.extend(line.to_le_bytes());
+    }
+}
+
+fn write_constant(constant: Value, out: &mut Vec<u8>) {
+    match constant.unpack() {
+        Unpacked::Nil => out.push(TAG_NIL),
+        Unpacked::Bool(false) => out.push(TAG_FALSE),
+        Unpacked::Bool(true) => out.push(TAG_TRUE),
+        Unpacked::Number(n) => {
+            out.push(TAG_NUMBER);
+            out.extend(n.to_bits().to_le_bytes());
+        }
+        Unpacked::String(s) => {
+            out.push(TAG_STRING);
+            write_string(s.as_str(), out);
+        }
+        Unpacked::Function(function) => {
+            out.push(TAG_FUNCTION);
+            out.push(function.arity);
+            write_u32(function.upvalue_count, out);
+            match &function.name {
+                Some(name) => {
+                    out.push(1);
+                    write_string(name, out);
+                }
+                None => out.push(0),
+            }
+            write_chunk(&function.chunk, out);
+        }
+        _ => panic!("can't serialize constant {constant}"),
+    }
+}
+
+struct Reader<'b, 'h> {
+    bytes: &'b [u8],
+    offset: usize,
+    heap: &'h mut Heap,
+    // of functions being read
+    depth: usize,
+}
+
+impl<'b, 'h> Reader<'b, 'h> {
+    fn malformed(&self, message: impl Into<String>) -> BytecodeError {
+        BytecodeError::Malformed {
+            offset: self.offset,
+            message: message.into(),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'b [u8], BytecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.offset..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or(BytecodeError::Truncated)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn len(&mut self) -> Result<usize, BytecodeError> {
+        self.u32().map(|n| n as usize)
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let start = self.offset;
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| {
+            BytecodeError::Malformed {
+                offset: start,
+                message: "string isn't valid utf-8".to_string(),
+            }
+        })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, BytecodeError> {
+        let mut chunk = Chunk::default();
+        let constant_count = self.len()?;
+        if constant_count > MAX_CONSTANTS {
+            return Err(self.malformed("too many constants"));
+        }
+        for _ in 0..constant_count {
+            let constant = self.constant()?;
+            chunk.add_constant(constant);
+        }
+
+        let len = self.len()?;
+        chunk.code = self.take(len)?.to_vec();
+
+        let run_count = self.len()?;
+        for _ in 0..run_count {
+            let offset = self.len()?;
+            let line = self.u32()?;
+            // runs have to cover the code from its start, in order
+            let expected = match chunk.lines.last() {
+                None => offset == 0,
+                Some(&(last, _)) => offset > last,
+            };
+            if !expected || offset >= chunk.code.len() {
+                return Err(self.malformed("line table out of order"));
+            }
+            chunk.lines.push((offset, line));
+        }
+        if chunk.lines.is_empty() && !chunk.code.is_empty() {
+            return Err(self.malformed("code without line table"));
+        }
+        Ok(chunk)
+    }
+
+    fn constant(&mut self) -> Result<Value, BytecodeError> {
+        let constant = match self.u8()? {
+            TAG_NIL => Value::NIL,
+            TAG_FALSE => Value::from(false),
+            TAG_TRUE => Value::from(true),
+            TAG_NUMBER => {
+                Value::from(f64::from_bits(u64::from_le_bytes(self.array()?)))
+            }
+            TAG_STRING => {
+                let string = self.string()?;
+                Value::from(self.heap.intern(string))
+            }
+            TAG_FUNCTION => Value::from(self.function()?),
+            tag => {
+                self.offset -= 1;
+                return Err(
+                    self.malformed(format!("unknown constant tag {tag}"))
+                );
+            }
+        };
+        Ok(constant)
+    }
+
+    fn function(&mut self) -> Result<Gc<ObjFunction>, BytecodeError> {
+        if self.depth == MAX_NESTING {
+            return Err(self.malformed("functions nested too deeply"));
+        }
+        let arity = self.u8()?;
+        let upvalue_count = self.len()?;
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => {
+                self.offset -= 1;
+                return Err(self.malformed("invalid function name"));
+            }
+        };
+        self.depth += 1;
+        let chunk = self.chunk()?;
+        self.depth -= 1;
+        Ok(self.heap.alloc(ObjFunction {
+            arity,
+            upvalue_count,
+            chunk,
+            name,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, Mode};
+
+    const SCRIPT: &str = "\
+var greeting = \"hi\";
+fun outer(a) {
+  fun inner() { return a * 2.5; }
+  return inner;
+}
+print outer(nil) == false;
+";
+
+    fn compiled(source: &str, heap: &mut Heap) -> Vec<u8> {
+        compile(source, heap, Mode::Script)
+            .unwrap()
+            .chunk
+            .serialize()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut heap = Heap::default();
+        let original = compile(SCRIPT, &mut heap, Mode::Script).unwrap();
+        let chunk =
+            Chunk::deserialize(&original.chunk.serialize(), &mut heap).unwrap();
+        assert_eq!(chunk.code, original.chunk.code);
+        assert_eq!(chunk.lines, original.chunk.lines);
+        assert_eq!(chunk.constants.len(), original.chunk.constants.len());
+        for (a, b) in chunk.constants.iter().zip(&original.chunk.constants) {
+            match (a.unpack(), b.unpack()) {
+                (Unpacked::Function(a), Unpacked::Function(b)) => {
+                    assert_eq!(a.name, b.name);
+                    assert_eq!(a.arity, b.arity);
+                    assert_eq!(a.upvalue_count, b.upvalue_count);
+                    assert_eq!(a.chunk.serialize(), b.chunk.serialize());
+                }
+                // strings are interned, so this compares them too
+                (a, b) => assert!(a == b),
+            }
+        }
+        // deduplication still finds deserialized constants
+        let greeting = Value::from(heap.intern("greeting".to_string()));
+        assert!(chunk.find_constant(&greeting).is_some());
+    }
+
+    #[test]
+    fn rejects_wrong_header() {
+        let mut heap = Heap::default();
+        let mut bytes = compiled("print 1;", &mut heap);
+        assert!(matches!(
+            Chunk::deserialize(b"print 1;", &mut heap),
+            Err(BytecodeError::NotBytecode)
+        ));
+        bytes[MAGIC.len()] ^= 0xff;
+        assert!(matches!(
+            Chunk::deserialize(&bytes, &mut heap),
+            Err(BytecodeError::Version(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let mut heap = Heap::default();
+        let bytes = compiled(SCRIPT, &mut heap);
+        for len in MAGIC.len()..bytes.len() {
+            assert!(
+                matches!(
+                    Chunk::deserialize(&bytes[..len], &mut heap),
+                    Err(BytecodeError::Truncated)
+                ),
+                "length {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut heap = Heap::default();
+        let mut bytes = compiled("print 1;", &mut heap);
+        bytes.push(0);
+        assert!(matches!(
+            Chunk::deserialize(&bytes, &mut heap),
+            Err(BytecodeError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_constant_tag() {
+        let mut heap = Heap::default();
+        let mut bytes = compiled("print 1;", &mut heap);
+        // tag of the first constant, right after its count
+        let tag = MAGIC.len() + 2 + 4;
+        assert_eq!(bytes[tag], TAG_NUMBER);
+        bytes[tag] = 42;
+        let Err(error) = Chunk::deserialize(&bytes, &mut heap) else {
+            panic!("deserialized unknown constant");
+        };
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Malformed bytecode at byte {tag}: unknown constant tag 42."
+            )
+        );
+    }
+}
@@ -1,2 +1,4 @@
-pub const DEBUG_PRINT_CODE: bool = true;
-pub const DEBUG_TRACE_EXECUTION: bool = true;
+pub const DEBUG_STRESS_GC: bool = false;
+pub const DEBUG_LOG_GC: bool = false;
+
+pub const GC_HEAP_GROW_FACTOR: f64 = 2.0;
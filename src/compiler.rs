@@ -1,44 +1,98 @@
-use std::mem;
+use std::{fmt, mem};
 
 use crate::{
-    chunk::{Chunk, Id, Opcode},
-    common::DEBUG_PRINT_CODE,
-    scanner::{Scanner, Token, TokenType},
+    chunk::{Chunk, Id, Opcode, MAX_CONSTANTS},
+    diagnostic::{Diagnostic, Severity},
+    memory::{Gc, Heap},
+    object::ObjFunction,
+    scanner::{Scanner, Span, Token, TokenType},
     value::Value,
 };
 
 #[derive(Debug)]
-pub struct CompileError;
+pub struct CompileError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CompileError {
+    /// Renders every diagnostic with an excerpt of `source` it points at.
+    pub fn render(&self, source: &str) -> String {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(source))
+            .collect()
+    }
+}
 
-// `'s` stands for `'source`
-struct Parser<'s, 'co, 'ch> {
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`compile`] expects `source` to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Declarations and statements, like a script file.
+    Script,
+    /// Same, except that if it's a single expression statement, the
+    /// function returns its value instead of discarding it. The trailing
+    /// semicolon is optional then.
+    Repl,
+    /// Single expression, which the function returns the value of.
+    Expression,
+}
+
+// `'s` stands for `'source`, `'h` for `'heap`
+struct Parser<'s, 'h> {
     scanner: Scanner<'s>,
-    compiler: &'co mut Compiler<'s>,
-    chunk: &'ch mut Chunk,
+    heap: &'h mut Heap,
+    compiler: Compiler<'s>,
+    classes: Vec<ClassCompiler>,
     current: Token<'s>,
     previous: Token<'s>,
+    diagnostics: Vec<Diagnostic>,
+    // where the left operand of infix expression being parsed starts
+    expression_start: usize,
+    mode: Mode,
+    // how deep expressions, statements and functions being parsed are
+    // nested, each level recursing once more, see `MAX_NESTING`
+    depth: usize,
+    // nesting went over the limit, so the rest of the source got skipped
+    too_deep: bool,
     had_error: bool,
     panic_mode: bool,
 }
 
+// more than enough for any script, while still far from overflowing the
+// stack, even in debug builds, and keeping scope depth within a byte
+const MAX_NESTING: usize = 200;
+
 const EMPTY_TOKEN: Token = Token {
     r#type: TokenType::Error,
     lexeme: "",
     line: 0,
+    column: 0,
+    span: Span { start: 0, end: 0 },
 };
 
-impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
-    fn new(
-        scanner: Scanner<'s>,
-        compiler: &'co mut Compiler<'s>,
-        chunk: &'ch mut Chunk,
-    ) -> Self {
+impl<'s, 'h> Parser<'s, 'h> {
+    fn new(scanner: Scanner<'s>, heap: &'h mut Heap, mode: Mode) -> Self {
         Self {
             scanner,
-            compiler,
-            chunk,
+            heap,
+            compiler: Compiler::new(FunctionType::Script, None),
+            classes: vec![],
             current: EMPTY_TOKEN,
             previous: EMPTY_TOKEN,
+            diagnostics: vec![],
+            expression_start: 0,
+            mode,
+            depth: 0,
+            too_deep: false,
             had_error: false,
             panic_mode: false,
         }
@@ -53,18 +107,25 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn error_at(&mut self, token: Token, message: &str) {
-        if self.panic_mode {
+        // everything after the source got skipped would be an error too
+        if self.panic_mode || self.too_deep {
             return;
         }
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
-        match token.r#type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at '{}'", token.lexeme),
-        }
-        eprintln!(": {message}");
+        let at = match token.r#type {
+            TokenType::Eof => Some(String::from("end")),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", token.lexeme)),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            at,
+            line: token.line,
+            column: token.column,
+            span: token.span,
+        });
         self.had_error = true;
     }
 
@@ -79,6 +140,29 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         }
     }
 
+    // goes one level deeper, unless it's too deep, in which case it
+    // returns false and skips the rest of the source, so that every level
+    // returns right away
+    fn enter(&mut self) -> bool {
+        if self.too_deep {
+            return false;
+        }
+        if self.depth == MAX_NESTING {
+            self.error_at_current("Code nested too deeply.");
+            self.too_deep = true;
+            while !self.check(TokenType::Eof) {
+                self.advance();
+            }
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
     fn check(&mut self, r#type: TokenType) -> bool {
         self.current.r#type == r#type
     }
@@ -98,8 +182,14 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         }
     }
 
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write_byte(byte, self.previous.line);
+        let line = self.previous.line;
+        let span = self.previous.span;
+        self.chunk().write_byte(byte, line, span);
     }
 
     fn emit_bytes(&mut self, bytes: &[u8]) {
@@ -108,54 +198,89 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         }
     }
 
-    fn emit_loop(&mut self, loop_start: u16) {
+    // for instructions finishing an expression, which span all of it,
+    // from `start` up to the last consumed token
+    fn emit_bytes_from(&mut self, start: usize, bytes: &[u8]) {
+        let line = self.previous.line;
+        let span = Span {
+            start,
+            end: self.previous.span.end,
+        };
+        for &byte in bytes {
+            self.chunk().write_byte(byte, line, span);
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(Opcode::Loop.as_u8());
 
-        let offset: u16 =
-            (self.chunk.len() - loop_start + 2).try_into().unwrap();
+        let offset = self.chunk().len() - loop_start + 2;
+        let offset = u16::try_from(offset).unwrap_or_else(|_| {
+            self.error("Loop body too large.");
+            0
+        });
         self.emit_bytes(&offset.to_le_bytes());
     }
 
-    fn emit_jump(&mut self, instruction: u8) -> u16 {
+    fn emit_jump(&mut self, instruction: u8) -> usize {
         self.emit_byte(instruction);
-        let loc = self.chunk.len();
+        let loc = self.chunk().len();
         self.emit_bytes(&[0xff, 0xff]);
         loc
     }
 
     fn make_constant(&mut self, value: Value) -> Id {
-        if let Some(id) = self.chunk.find_constant(&value) {
+        if let Some(id) = self.chunk().find_constant(&value) {
             return id;
         }
-        if self.chunk.constants_len() == Id::MAX {
+        if self.chunk().constants_len() == MAX_CONSTANTS {
             self.error("Too many constants in one chunk.");
             return 0;
         }
-        self.chunk.add_constant(value)
+        self.chunk().add_constant(value)
     }
 
     fn emit_constant(&mut self, value: Value) {
         let id = self.make_constant(value);
-        self.emit_bytes(&[Opcode::Constant.as_u8(), id]);
+        let bytes = with_constant(Opcode::Constant, Opcode::ConstantLong, id);
+        self.emit_bytes(&bytes);
     }
 
-    fn patch_jump(&mut self, offset: u16) {
-        let jump = self.chunk.len() - offset - 2;
-        self.chunk.code_mut()[offset as usize..][..2]
+    fn patch_jump(&mut self, offset: usize) {
+        let jump = self.chunk().len() - offset - 2;
+        let jump = u16::try_from(jump).unwrap_or_else(|_| {
+            self.error("Too much code to jump over.");
+            0
+        });
+        self.chunk().code_mut()[offset..][..2]
             .copy_from_slice(&jump.to_le_bytes());
     }
 
     fn emit_return(&mut self) {
+        if self.compiler.r#type == FunctionType::Initializer {
+            self.emit_bytes(&[Opcode::GetLocal.as_u8(), 0]);
+        } else {
+            self.emit_byte(Opcode::Nil.as_u8());
+        }
         self.emit_byte(Opcode::Return.as_u8());
     }
 
-    fn end_compiler(&mut self) {
+    fn init_compiler(&mut self, r#type: FunctionType) {
+        let name = self.previous.lexeme.to_string();
+        let compiler = Compiler::new(r#type, Some(name));
+        let enclosing = mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+    }
+
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
         self.emit_return();
-        if DEBUG_PRINT_CODE {
-            if !self.had_error {
-                self.chunk.disassembly("code");
-            }
+        let mut function = mem::take(&mut self.compiler.function);
+        let upvalues = mem::take(&mut self.compiler.upvalues);
+        function.upvalue_count = upvalues.len();
+        if let Some(enclosing) = self.compiler.enclosing.take() {
+            self.compiler = *enclosing;
         }
+        (function, upvalues)
     }
 
     fn begin_scope(&mut self) {
@@ -165,21 +290,24 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        // pop all locals from current scope
-        while self
-            .compiler
-            .locals
-            .last()
-            .map_or(false, |local| local.depth > self.compiler.scope_depth)
-        {
-            self.emit_byte(Opcode::Pop.as_u8());
+        // pop all locals from current scope, moving captured ones to the heap
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth <= self.compiler.scope_depth {
+                break;
+            }
+            let op = if local.is_captured {
+                Opcode::CloseUpvalue
+            } else {
+                Opcode::Pop
+            };
+            self.emit_byte(op.as_u8());
             self.compiler.locals.pop().unwrap();
         }
     }
 
     fn number(&mut self, _can_assign: bool) {
-        let value = self.previous.lexeme.parse().unwrap();
-        self.emit_constant(Value::Number(value));
+        let value: f64 = self.previous.lexeme.parse().unwrap();
+        self.emit_constant(Value::from(value));
     }
 
     fn or_(&mut self, _: bool) {
@@ -201,25 +329,29 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
             .unwrap()
             .strip_suffix('"')
             .unwrap();
-        self.emit_constant(Value::string(String::from(s)))
+        let string = self.heap.intern(String::from(s));
+        self.emit_constant(Value::from(string))
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let (arg, get_op, set_op);
-        if let Some(local_arg) = self.resolve_local(&name) {
-            arg = local_arg;
-            get_op = Opcode::GetLocal;
-            set_op = Opcode::SetLocal;
+        let start = name.span.start;
+        let (get, set);
+        if let Some(arg) = self.resolve_local(&name) {
+            get = vec![Opcode::GetLocal.as_u8(), arg];
+            set = vec![Opcode::SetLocal.as_u8(), arg];
+        } else if let Some(arg) = self.resolve_upvalue(&name) {
+            get = vec![Opcode::GetUpvalue.as_u8(), arg];
+            set = vec![Opcode::SetUpvalue.as_u8(), arg];
         } else {
-            arg = self.identifier_constant(name);
-            get_op = Opcode::GetGlobal;
-            set_op = Opcode::SetGlobal;
+            let id = self.identifier_constant(name);
+            get = with_constant(Opcode::GetGlobal, Opcode::GetGlobalLong, id);
+            set = with_constant(Opcode::SetGlobal, Opcode::SetGlobalLong, id);
         };
         if can_assign && self.match_(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(&[set_op.as_u8(), arg]);
+            self.emit_bytes_from(start, &set);
         } else {
-            self.emit_bytes(&[get_op.as_u8(), arg]);
+            self.emit_bytes(&get);
         }
     }
 
@@ -227,6 +359,71 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         self.named_variable(self.previous.clone(), can_assign);
     }
 
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => (),
+        }
+        let start = self.previous.span.start;
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous.clone());
+
+        self.named_variable(synthetic_token("this"), false);
+        if self.match_(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super"), false);
+            let mut bytes = with_constant(
+                Opcode::SuperInvoke,
+                Opcode::SuperInvokeLong,
+                name,
+            );
+            bytes.push(arg_count);
+            self.emit_bytes_from(start, &bytes);
+        } else {
+            self.named_variable(synthetic_token("super"), false);
+            let bytes =
+                with_constant(Opcode::GetSuper, Opcode::GetSuperLong, name);
+            self.emit_bytes_from(start, &bytes);
+        }
+    }
+
+    fn this_(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.variable(false);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        let start = self.expression_start;
+        self.emit_bytes_from(start, &[Opcode::Call.as_u8(), arg_count]);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous.clone());
+        let start = self.expression_start;
+        let bytes = if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            with_constant(Opcode::SetProperty, Opcode::SetPropertyLong, name)
+        } else if self.match_(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            let mut bytes =
+                with_constant(Opcode::Invoke, Opcode::InvokeLong, name);
+            bytes.push(arg_count);
+            bytes
+        } else {
+            with_constant(Opcode::GetProperty, Opcode::GetPropertyLong, name)
+        };
+        self.emit_bytes_from(start, &bytes);
+    }
+
     fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
@@ -234,36 +431,36 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
 
     fn unary(&mut self, _can_assign: bool) {
         let operator = self.previous.r#type;
+        let start = self.previous.span.start;
         self.parse_precedence(Precedence::Unary);
-        match operator {
-            TokenType::Bang => self.emit_byte(Opcode::Not.as_u8()),
-            TokenType::Minus => self.emit_byte(Opcode::Negate.as_u8()),
+        let op = match operator {
+            TokenType::Bang => Opcode::Not,
+            TokenType::Minus => Opcode::Negate,
             _ => unreachable!(),
-        }
+        };
+        self.emit_bytes_from(start, &[op.as_u8()]);
     }
 
     fn binary(&mut self, _can_assign: bool) {
         let operator = self.previous.r#type;
         let rule = get_rule(operator);
+        let start = self.expression_start;
         self.parse_precedence(rule.precedence.next());
-        match operator {
-            TokenType::BangEqual => {
-                self.emit_bytes(&[Opcode::Equal.as_u8(), Opcode::Not.as_u8()])
-            }
-            TokenType::EqualEqual => self.emit_byte(Opcode::Equal.as_u8()),
-            TokenType::Greater => self.emit_byte(Opcode::Greater.as_u8()),
-            TokenType::GreaterEqual => {
-                self.emit_bytes(&[Opcode::Less.as_u8(), Opcode::Not.as_u8()])
-            }
-            TokenType::Less => self.emit_byte(Opcode::Less.as_u8()),
-            TokenType::LessEqual => {
-                self.emit_bytes(&[Opcode::Greater.as_u8(), Opcode::Not.as_u8()])
-            }
-            TokenType::Plus => self.emit_byte(Opcode::Add.as_u8()),
-            TokenType::Minus => self.emit_byte(Opcode::Subtract.as_u8()),
-            TokenType::Star => self.emit_byte(Opcode::Multiply.as_u8()),
-            TokenType::Slash => self.emit_byte(Opcode::Divide.as_u8()),
+        let ops: &[Opcode] = match operator {
+            TokenType::BangEqual => &[Opcode::Equal, Opcode::Not],
+            TokenType::EqualEqual => &[Opcode::Equal],
+            TokenType::Greater => &[Opcode::Greater],
+            TokenType::GreaterEqual => &[Opcode::Less, Opcode::Not],
+            TokenType::Less => &[Opcode::Less],
+            TokenType::LessEqual => &[Opcode::Greater, Opcode::Not],
+            TokenType::Plus => &[Opcode::Add],
+            TokenType::Minus => &[Opcode::Subtract],
+            TokenType::Star => &[Opcode::Multiply],
+            TokenType::Slash => &[Opcode::Divide],
             _ => unreachable!(),
+        };
+        for op in ops {
+            self.emit_bytes_from(start, &[op.as_u8()]);
         }
     }
 
@@ -277,10 +474,15 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.enter() {
+            return;
+        }
+        let start = self.current.span.start;
         self.advance();
         let prefix_rule = get_rule(self.previous.r#type).prefix;
         let Some(rule) = prefix_rule else {
             self.error("Expect expression.");
+            self.leave();
             return;
         };
 
@@ -290,29 +492,36 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         while precedence <= get_rule(self.current.r#type).precedence {
             self.advance();
             let infix_rule = get_rule(self.previous.r#type).infix;
+            let outer = mem::replace(&mut self.expression_start, start);
             infix_rule.unwrap()(self, can_assign);
+            self.expression_start = outer;
         }
 
         if can_assign && self.match_(TokenType::Equal) {
             self.error("Invalid assignment target");
         }
+        self.leave();
     }
 
     fn identifier_constant(&mut self, name: Token) -> Id {
-        self.make_constant(Value::string(name.lexeme.to_string()))
+        let name = self.heap.intern(name.lexeme.to_string());
+        self.make_constant(Value::from(name))
     }
 
     fn resolve_local(&mut self, name: &Token) -> Option<u8> {
-        let slot = self
-            .compiler
-            .locals
-            .iter()
-            .rposition(|local| name.lexeme == local.name.lexeme)
-            .map(|x| x.try_into().unwrap());
-        if slot == Some(-1i8 as u8) {
-            self.error("Can't read local variable in its own initializer.");
-        }
-        slot
+        self.compiler.resolve_local(name).unwrap_or_else(|message| {
+            self.error(message);
+            Some(0)
+        })
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<u8> {
+        self.compiler
+            .resolve_upvalue(name)
+            .unwrap_or_else(|message| {
+                self.error(message);
+                Some(0)
+            })
     }
 
     fn add_local(&mut self, name: Token<'s>) {
@@ -323,6 +532,7 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         let local = Local {
             name,
             depth: -1i8 as u8,
+            is_captured: false,
         };
         self.compiler.locals.push(local);
     }
@@ -357,6 +567,9 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn mark_initialized(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
         self.compiler.locals.last_mut().unwrap().depth =
             self.compiler.scope_depth;
     }
@@ -366,7 +579,31 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(&[Opcode::DefineGlobal.as_u8(), global])
+        let bytes = with_constant(
+            Opcode::DefineGlobal,
+            Opcode::DefineGlobalLong,
+            global,
+        );
+        self.emit_bytes(&bytes)
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
     }
 
     fn and_(&mut self, _: bool) {
@@ -390,6 +627,121 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
 
+    fn function(&mut self, r#type: FunctionType) {
+        if !self.enter() {
+            return;
+        }
+        self.init_compiler(r#type);
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.compiler.function.arity == u8::MAX {
+                    self.error_at_current(
+                        "Can't have more than 255 parameters.",
+                    );
+                } else {
+                    self.compiler.function.arity += 1;
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant.unwrap_or(0));
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // no `end_scope`, the whole frame gets discarded on return
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.alloc(function);
+        let id = self.make_constant(Value::from(function));
+        self.emit_bytes(&with_constant(
+            Opcode::Closure,
+            Opcode::ClosureLong,
+            id,
+        ));
+        for upvalue in upvalues {
+            self.emit_bytes(&[upvalue.is_local as u8, upvalue.index]);
+        }
+        self.leave();
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.previous.clone());
+        let r#type = if self.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(r#type);
+        self.emit_bytes(&with_constant(
+            Opcode::Method,
+            Opcode::MethodLong,
+            constant,
+        ));
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(self.previous.clone());
+        self.declare_variable();
+
+        self.emit_bytes(&with_constant(
+            Opcode::Class,
+            Opcode::ClassLong,
+            name_constant,
+        ));
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if class_name.lexeme == self.previous.lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // new scope, so each class gets its own `super` local
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_byte(Opcode::Inherit.as_u8());
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // load the class back, so methods can be bound to it
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof)
+        {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(Opcode::Pop.as_u8());
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global.unwrap_or(0));
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
         if self.match_(TokenType::Equal) {
@@ -405,9 +757,30 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn print_statement(&mut self) {
+        // `print` keyword, so errors writing output point at the statement
+        let start = self.previous.span.start;
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(Opcode::Print.as_u8());
+        self.emit_bytes_from(start, &[Opcode::Print.as_u8()]);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.r#type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+        if self.match_(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.r#type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after return value.",
+            );
+            self.emit_byte(Opcode::Return.as_u8());
+        }
     }
 
     fn switch_statement(&mut self) {
@@ -445,6 +818,7 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         }
         if let Some(case_jump) = case_jump {
             self.patch_jump(case_jump);
+            self.emit_byte(Opcode::Pop.as_u8()); // drop comparison result
         }
         self.emit_byte(Opcode::Pop.as_u8()); // drop switch condition
         if self.match_(TokenType::Default) {
@@ -459,7 +833,7 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn while_statement(&mut self) {
-        let loop_start = self.chunk.len();
+        let loop_start = self.chunk().len();
         self.consume(TokenType::LeftParen, "Expect '(' after `while`.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
@@ -496,9 +870,21 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn expression_statement(&mut self) {
+        // only the first statement at the top level starts with empty chunk
+        let first = self.mode == Mode::Repl
+            && self.compiler.enclosing.is_none()
+            && self.compiler.scope_depth == 0
+            && self.chunk().is_empty();
         self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(Opcode::Pop.as_u8());
+        // at the prompt, `1 + 2` is as good as `1 + 2;`
+        if !(first && self.check(TokenType::Eof)) {
+            self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        }
+        if first && self.check(TokenType::Eof) {
+            self.emit_byte(Opcode::Return.as_u8());
+        } else {
+            self.emit_byte(Opcode::Pop.as_u8());
+        }
     }
 
     fn for_statement(&mut self) {
@@ -512,7 +898,7 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
             self.expression_statement();
         }
 
-        let mut loop_start = self.chunk.len();
+        let mut loop_start = self.chunk().len();
         let mut exit_jump = None;
         if !self.match_(TokenType::Semicolon) {
             self.expression();
@@ -526,7 +912,7 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         }
         if !self.match_(TokenType::Semicolon) {
             let body_jump = self.emit_jump(Opcode::Jump.as_u8());
-            let increment_start = self.chunk.len();
+            let increment_start = self.chunk().len();
             self.expression();
             self.emit_byte(Opcode::Pop.as_u8());
             self.consume(TokenType::RightParen, "Expect ')' after clauses.");
@@ -565,7 +951,11 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn declaration(&mut self) {
-        if self.match_(TokenType::Var) {
+        if self.match_(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_(TokenType::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -576,12 +966,17 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
     }
 
     fn statement(&mut self) {
+        if !self.enter() {
+            return;
+        }
         if self.match_(TokenType::Print) {
             self.print_statement();
         } else if self.match_(TokenType::For) {
             self.for_statement();
         } else if self.match_(TokenType::If) {
             self.if_statement();
+        } else if self.match_(TokenType::Return) {
+            self.return_statement();
         } else if self.match_(TokenType::Switch) {
             self.switch_statement();
         } else if self.match_(TokenType::While) {
@@ -593,18 +988,19 @@ impl<'s, 'co, 'ch> Parser<'s, 'co, 'ch> {
         } else {
             self.expression_statement();
         }
+        self.leave();
     }
 }
 
-type ParseFn<'s, 'co, 'ch> = for<'a> fn(&'a mut Parser<'s, 'co, 'ch>, bool);
+type ParseFn<'s, 'h> = for<'a> fn(&'a mut Parser<'s, 'h>, bool);
 
-struct ParseRule<'s, 'co, 'ch> {
-    prefix: Option<ParseFn<'s, 'co, 'ch>>,
-    infix: Option<ParseFn<'s, 'co, 'ch>>,
+struct ParseRule<'s, 'h> {
+    prefix: Option<ParseFn<'s, 'h>>,
+    infix: Option<ParseFn<'s, 'h>>,
     precedence: Precedence,
 }
 
-fn get_rule<'s, 'co, 'ch>(r#type: TokenType) -> ParseRule<'s, 'co, 'ch> {
+fn get_rule<'s, 'h>(r#type: TokenType) -> ParseRule<'s, 'h> {
     use Parser as P;
     use Precedence as Pr;
     use TokenType as TT;
@@ -612,13 +1008,13 @@ fn get_rule<'s, 'co, 'ch>(r#type: TokenType) -> ParseRule<'s, 'co, 'ch> {
     #[rustfmt::skip]
     let (prefix, infix, precedence): (Option<ParseFn>, Option<ParseFn>, _) =
     match r#type {
-        TT::LeftParen =>    (Some(P::grouping),            None, Pr::None),
+        TT::LeftParen =>    (Some(P::grouping),   Some(P::call), Pr::Call),
         TT::RightParen =>   (             None,            None, Pr::None),
         TT::LeftBrace =>    (             None,            None, Pr::None),
         TT::RightBrace =>   (             None,            None, Pr::None),
         TT::Colon =>        (             None,            None, Pr::None),
         TT::Comma =>        (             None,            None, Pr::None),
-        TT::Dot =>          (             None,            None, Pr::None),
+        TT::Dot =>          (             None,     Some(P::dot), Pr::Call),
         TT::Minus =>        (   Some(P::unary), Some(P::binary), Pr::Term),
         TT::Plus =>         (             None, Some(P::binary), Pr::Term),
         TT::Semicolon =>    (             None,            None, Pr::None),
@@ -648,9 +1044,9 @@ fn get_rule<'s, 'co, 'ch>(r#type: TokenType) -> ParseRule<'s, 'co, 'ch> {
         TT::Or =>           (             None,    Some(P::or_), Pr::Or),
         TT::Print =>        (             None,            None, Pr::None),
         TT::Return =>       (             None,            None, Pr::None),
-        TT::Super =>        (             None,            None, Pr::None),
+        TT::Super =>        (  Some(P::super_),            None, Pr::None),
         TT::Switch =>       (             None,            None, Pr::None),
-        TT::This =>         (             None,            None, Pr::None),
+        TT::This =>         (   Some(P::this_),            None, Pr::None),
         TT::True =>         ( Some(P::literal),            None, Pr::None),
         TT::Var =>          (             None,            None, Pr::None),
         TT::While =>        (             None,            None, Pr::None),
@@ -697,39 +1093,169 @@ impl Precedence {
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
 struct Compiler<'s> {
+    enclosing: Option<Box<Compiler<'s>>>,
+    function: ObjFunction,
+    r#type: FunctionType,
     locals: Vec<Local<'s>>,
+    upvalues: Vec<Upvalue>,
     scope_depth: u8,
 }
 
 impl<'s> Compiler<'s> {
-    fn new() -> Self {
+    fn new(r#type: FunctionType, name: Option<String>) -> Self {
         Self {
-            locals: vec![],
+            enclosing: None,
+            function: ObjFunction {
+                name,
+                ..Default::default()
+            },
+            r#type,
+            // slot zero is reserved for the called function itself,
+            // or for the receiver in methods
+            locals: vec![Local {
+                name: match r#type {
+                    FunctionType::Function | FunctionType::Script => {
+                        EMPTY_TOKEN
+                    }
+                    FunctionType::Initializer | FunctionType::Method => {
+                        synthetic_token("this")
+                    }
+                },
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: vec![],
             scope_depth: 0,
         }
     }
+
+    // `Err` holds message of the compile error to report
+    fn resolve_local(&self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let Some(slot) = self
+            .locals
+            .iter()
+            .rposition(|local| name.lexeme == local.name.lexeme)
+        else {
+            return Ok(None);
+        };
+        if self.locals[slot].depth == -1i8 as u8 {
+            return Err("Can't read local variable in its own initializer.");
+        }
+        Ok(Some(slot.try_into().unwrap()))
+    }
+
+    // same as `resolve_local`
+    fn resolve_upvalue(
+        &mut self,
+        name: &Token,
+    ) -> Result<Option<u8>, &'static str> {
+        let Some(enclosing) = self.enclosing.as_deref_mut() else {
+            return Ok(None);
+        };
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[usize::from(local)].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(
+        &mut self,
+        index: u8,
+        is_local: bool,
+    ) -> Result<u8, &'static str> {
+        let upvalue = Upvalue { index, is_local };
+        if let Some(id) = self.upvalues.iter().position(|x| *x == upvalue) {
+            return Ok(id.try_into().unwrap());
+        }
+        if self.upvalues.len() == 256 {
+            return Err("Too many closure variables in function.");
+        }
+        self.upvalues.push(upvalue);
+        Ok((self.upvalues.len() - 1).try_into().unwrap())
+    }
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
 }
 
 struct Local<'s> {
     name: Token<'s>,
     depth: u8,
+    is_captured: bool,
+}
+
+#[derive(PartialEq)]
+struct Upvalue {
+    // local slot in the enclosing function if `is_local`,
+    // otherwise index into its upvalues
+    index: u8,
+    is_local: bool,
 }
 
-pub fn compile(source: &str, chunk: &mut Chunk) -> Result<(), CompileError> {
+// token for a name that's not in the source code
+fn synthetic_token(text: &'static str) -> Token<'static> {
+    Token {
+        r#type: TokenType::Identifier,
+        lexeme: text,
+        line: 0,
+        column: 0,
+        span: Span::default(),
+    }
+}
+
+// instruction with constant `id` as its operand, switching to the long form,
+// with 24-bit little-endian operand, once `id` doesn't fit in a byte
+fn with_constant(short: Opcode, long: Opcode, id: Id) -> Vec<u8> {
+    match u8::try_from(id) {
+        Ok(id) => vec![short.as_u8(), id],
+        Err(_) => {
+            let [a, b, c, _] = id.to_le_bytes();
+            vec![long.as_u8(), a, b, c]
+        }
+    }
+}
+
+// doesn't collect garbage, so objects allocated during compilation
+// don't need to be rooted
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+    mode: Mode,
+) -> Result<Gc<ObjFunction>, CompileError> {
     let scanner = Scanner::new(source);
-    let mut compiler = Compiler::new();
-    let mut parser = Parser::new(scanner, &mut compiler, chunk);
+    let mut parser = Parser::new(scanner, heap, mode);
 
     parser.advance();
-    while !parser.match_(TokenType::Eof) {
-        parser.declaration();
+    if mode == Mode::Expression {
+        parser.expression();
+        parser.consume(TokenType::Eof, "Expect end of expression.");
+        parser.emit_byte(Opcode::Return.as_u8());
+    } else {
+        while !parser.match_(TokenType::Eof) {
+            parser.declaration();
+        }
     }
-    parser.end_compiler();
+    let (function, _) = parser.end_compiler();
 
     if parser.had_error {
-        Err(CompileError)
+        Err(CompileError {
+            diagnostics: parser.diagnostics,
+        })
     } else {
-        Ok(())
+        Ok(parser.heap.alloc(function))
     }
 }
@@ -1,79 +1,184 @@
+use std::io::{self, Write};
+
 use crate::{
     chunk::{Chunk, Opcode},
-    value::print_value,
+    value::Unpacked,
 };
 
-pub fn disassembly_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{offset:04}: ");
+pub fn disassembly_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    write!(out, "{offset:04}: ")?;
     match chunk.get_line_if_first(offset) {
-        Some(line) => print!("{:4} ", line),
-        None => print!("   | "),
+        Some(line) => write!(out, "{:4} ", line)?,
+        None => write!(out, "   | ")?,
     }
     let op = chunk.code()[offset];
     let size = match Opcode::from_u8(op) {
         Some(Opcode::Constant) => {
-            constant_instruction("OP_CONSTANT", chunk, offset)
+            constant_instruction("OP_CONSTANT", chunk, offset, out)
         }
-        Some(Opcode::Nil) => simple_instruction("OP_NIL", offset),
-        Some(Opcode::True) => simple_instruction("OP_TRUE", offset),
-        Some(Opcode::False) => simple_instruction("OP_FALSE", offset),
-        Some(Opcode::Dup) => simple_instruction("OP_DUP", offset),
-        Some(Opcode::Pop) => simple_instruction("OP_POP", offset),
+        Some(Opcode::ConstantLong) => {
+            constant_long_instruction("OP_CONSTANT_LONG", chunk, offset, out)
+        }
+        Some(Opcode::Nil) => simple_instruction("OP_NIL", offset, out),
+        Some(Opcode::True) => simple_instruction("OP_TRUE", offset, out),
+        Some(Opcode::False) => simple_instruction("OP_FALSE", offset, out),
+        Some(Opcode::Dup) => simple_instruction("OP_DUP", offset, out),
+        Some(Opcode::Pop) => simple_instruction("OP_POP", offset, out),
         Some(Opcode::GetLocal) => {
-            byte_instruction("OP_GET_LOCAL", chunk, offset)
+            byte_instruction("OP_GET_LOCAL", chunk, offset, out)
         }
         Some(Opcode::SetLocal) => {
-            byte_instruction("OP_SET_LOCAL", chunk, offset)
+            byte_instruction("OP_SET_LOCAL", chunk, offset, out)
         }
         Some(Opcode::GetGlobal) => {
-            constant_instruction("OP_GET_GLOBAL", chunk, offset)
+            constant_instruction("OP_GET_GLOBAL", chunk, offset, out)
+        }
+        Some(Opcode::GetGlobalLong) => {
+            constant_long_instruction("OP_GET_GLOBAL_LONG", chunk, offset, out)
         }
         Some(Opcode::DefineGlobal) => {
-            constant_instruction("OP_DEFINE_GLOBAL", chunk, offset)
+            constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, out)
         }
+        Some(Opcode::DefineGlobalLong) => constant_long_instruction(
+            "OP_DEFINE_GLOBAL_LONG",
+            chunk,
+            offset,
+            out,
+        ),
         Some(Opcode::SetGlobal) => {
-            constant_instruction("OP_SET_GLOBAL", chunk, offset)
-        }
-        Some(Opcode::Equal) => simple_instruction("OP_EQUAL", offset),
-        Some(Opcode::Greater) => simple_instruction("OP_GREATER", offset),
-        Some(Opcode::Less) => simple_instruction("OP_LESS", offset),
-        Some(Opcode::Add) => simple_instruction("OP_ADD", offset),
-        Some(Opcode::Subtract) => simple_instruction("OP_SUBTRACT", offset),
-        Some(Opcode::Multiply) => simple_instruction("OP_MULTIPLY", offset),
-        Some(Opcode::Divide) => simple_instruction("OP_DIVIDE", offset),
-        Some(Opcode::Not) => simple_instruction("OP_NOT", offset),
-        Some(Opcode::Negate) => simple_instruction("OP_NEGATE", offset),
-        Some(Opcode::Print) => simple_instruction("OP_PRINT", offset),
-        Some(Opcode::Jump) => {
-            jump_instruction("OP_JUMP", chunk, JumpDirection::Forward, offset)
+            constant_instruction("OP_SET_GLOBAL", chunk, offset, out)
+        }
+        Some(Opcode::SetGlobalLong) => {
+            constant_long_instruction("OP_SET_GLOBAL_LONG", chunk, offset, out)
+        }
+        Some(Opcode::GetUpvalue) => {
+            byte_instruction("OP_GET_UPVALUE", chunk, offset, out)
+        }
+        Some(Opcode::SetUpvalue) => {
+            byte_instruction("OP_SET_UPVALUE", chunk, offset, out)
+        }
+        Some(Opcode::GetProperty) => {
+            constant_instruction("OP_GET_PROPERTY", chunk, offset, out)
+        }
+        Some(Opcode::GetPropertyLong) => constant_long_instruction(
+            "OP_GET_PROPERTY_LONG",
+            chunk,
+            offset,
+            out,
+        ),
+        Some(Opcode::SetProperty) => {
+            constant_instruction("OP_SET_PROPERTY", chunk, offset, out)
+        }
+        Some(Opcode::SetPropertyLong) => constant_long_instruction(
+            "OP_SET_PROPERTY_LONG",
+            chunk,
+            offset,
+            out,
+        ),
+        Some(Opcode::GetSuper) => {
+            constant_instruction("OP_GET_SUPER", chunk, offset, out)
+        }
+        Some(Opcode::GetSuperLong) => {
+            constant_long_instruction("OP_GET_SUPER_LONG", chunk, offset, out)
+        }
+        Some(Opcode::Equal) => simple_instruction("OP_EQUAL", offset, out),
+        Some(Opcode::Greater) => simple_instruction("OP_GREATER", offset, out),
+        Some(Opcode::Less) => simple_instruction("OP_LESS", offset, out),
+        Some(Opcode::Add) => simple_instruction("OP_ADD", offset, out),
+        Some(Opcode::Subtract) => {
+            simple_instruction("OP_SUBTRACT", offset, out)
         }
+        Some(Opcode::Multiply) => {
+            simple_instruction("OP_MULTIPLY", offset, out)
+        }
+        Some(Opcode::Divide) => simple_instruction("OP_DIVIDE", offset, out),
+        Some(Opcode::Not) => simple_instruction("OP_NOT", offset, out),
+        Some(Opcode::Negate) => simple_instruction("OP_NEGATE", offset, out),
+        Some(Opcode::Print) => simple_instruction("OP_PRINT", offset, out),
+        Some(Opcode::Jump) => jump_instruction(
+            "OP_JUMP",
+            chunk,
+            JumpDirection::Forward,
+            offset,
+            out,
+        ),
         Some(Opcode::JumpIfFalse) => jump_instruction(
             "OP_JUMP_IF_FALSE",
             chunk,
             JumpDirection::Forward,
             offset,
+            out,
+        ),
+        Some(Opcode::Loop) => jump_instruction(
+            "OP_LOOP",
+            chunk,
+            JumpDirection::Backward,
+            offset,
+            out,
         ),
-        Some(Opcode::Loop) => {
-            jump_instruction("OP_LOOP", chunk, JumpDirection::Backward, offset)
+        Some(Opcode::Call) => byte_instruction("OP_CALL", chunk, offset, out),
+        Some(Opcode::Invoke) => {
+            invoke_instruction("OP_INVOKE", chunk, offset, out)
         }
-        Some(Opcode::Return) => simple_instruction("OP_RETURN", offset),
-        None => {
-            println!("unknown opcode: {op}");
-            1
+        Some(Opcode::InvokeLong) => {
+            invoke_instruction("OP_INVOKE_LONG", chunk, offset, out)
         }
-    };
-    offset + size
+        Some(Opcode::SuperInvoke) => {
+            invoke_instruction("OP_SUPER_INVOKE", chunk, offset, out)
+        }
+        Some(Opcode::SuperInvokeLong) => {
+            invoke_instruction("OP_SUPER_INVOKE_LONG", chunk, offset, out)
+        }
+        Some(Opcode::Closure) => {
+            closure_instruction("OP_CLOSURE", chunk, offset, out)
+        }
+        Some(Opcode::ClosureLong) => {
+            closure_instruction("OP_CLOSURE_LONG", chunk, offset, out)
+        }
+        Some(Opcode::CloseUpvalue) => {
+            simple_instruction("OP_CLOSE_UPVALUE", offset, out)
+        }
+        Some(Opcode::Class) => {
+            constant_instruction("OP_CLASS", chunk, offset, out)
+        }
+        Some(Opcode::ClassLong) => {
+            constant_long_instruction("OP_CLASS_LONG", chunk, offset, out)
+        }
+        Some(Opcode::Inherit) => simple_instruction("OP_INHERIT", offset, out),
+        Some(Opcode::Method) => {
+            constant_instruction("OP_METHOD", chunk, offset, out)
+        }
+        Some(Opcode::MethodLong) => {
+            constant_long_instruction("OP_METHOD_LONG", chunk, offset, out)
+        }
+        Some(Opcode::Return) => simple_instruction("OP_RETURN", offset, out),
+        None => writeln!(out, "unknown opcode: {op}").map(|()| 1),
+    }?;
+    Ok(offset + size)
 }
 
-fn simple_instruction(name: &str, _offset: usize) -> usize {
-    println!("{name}");
-    1
+fn simple_instruction(
+    name: &str,
+    _offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    writeln!(out, "{name}")?;
+    Ok(1)
 }
 
-fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn byte_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
     let slot = chunk.code()[offset + 1];
-    println!("{name:16} {slot:4}");
-    2
+    writeln!(out, "{name:16} {slot:4}")?;
+    Ok(2)
 }
 
 enum JumpDirection {
@@ -86,21 +191,89 @@ fn jump_instruction(
     chunk: &Chunk,
     dir: JumpDirection,
     offset: usize,
-) -> usize {
+    out: &mut dyn Write,
+) -> io::Result<usize> {
     let bytes = chunk.code()[offset + 1..][..2].try_into().unwrap();
     let jump: usize = u16::from_le_bytes(bytes).into();
     let target = match dir {
         JumpDirection::Forward => offset + jump + 3,
-        JumpDirection::Backward => offset - jump + 3,
+        JumpDirection::Backward => offset + 3 - jump,
     };
-    println!("{name:16} {offset:4} -> {target}");
-    3
+    writeln!(out, "{name:16} {offset:4} -> {target}")?;
+    Ok(3)
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn constant_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
     let constant = chunk.code()[offset + 1];
-    print!("{name:16} {constant:4} '");
-    print_value(&chunk.get_constant(constant.into()));
-    println!("'");
-    2
+    let value = chunk.get_constant(constant.into());
+    writeln!(out, "{name:16} {constant:4} '{value}'")?;
+    Ok(2)
+}
+
+fn constant_long_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let [a, b, c] = chunk.code()[offset + 1..][..3].try_into().unwrap();
+    let constant = u32::from_le_bytes([a, b, c, 0]);
+    let value = chunk.get_constant(constant);
+    writeln!(out, "{name:16} {constant:4} '{value}'")?;
+    Ok(4)
+}
+
+fn invoke_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let (constant, size) = constant_operand(chunk, offset);
+    let arg_count = chunk.code()[offset + size];
+    let value = chunk.get_constant(constant);
+    writeln!(out, "{name:16} ({arg_count} args) {constant:4} '{value}'")?;
+    Ok(size + 1)
+}
+
+fn closure_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let (constant, mut size) = constant_operand(chunk, offset);
+    let value = chunk.get_constant(constant);
+    writeln!(out, "{name:16} {constant:4} {value}")?;
+
+    let Unpacked::Function(function) = value.unpack() else {
+        return Ok(size);
+    };
+    for _ in 0..function.upvalue_count {
+        let is_local = chunk.code()[offset + size];
+        let index = chunk.code()[offset + size + 1];
+        let kind = if is_local == 1 { "local" } else { "upvalue" };
+        writeln!(
+            out,
+            "{:04}:    |                       {kind} {index}",
+            offset + size
+        )?;
+        size += 2;
+    }
+    Ok(size)
+}
+
+// constant operand of the instruction at `offset`, and where the operands
+// after it start, with the long forms taking three bytes for it
+fn constant_operand(chunk: &Chunk, offset: usize) -> (u32, usize) {
+    let code = &chunk.code()[offset..];
+    match Opcode::from_u8(code[0]).is_some_and(Opcode::is_long) {
+        true => (u32::from_le_bytes([code[1], code[2], code[3], 0]), 4),
+        false => (code[1].into(), 2),
+    }
 }
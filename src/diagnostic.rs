@@ -0,0 +1,89 @@
+use std::fmt;
+
+use crate::scanner::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("Error"),
+            Severity::Warning => f.write_str("Warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// What the diagnostic points at, like `'foo'` or `end`,
+    /// `None` if the token itself couldn't be scanned.
+    pub at: Option<String>,
+    pub line: u32,
+    /// Starts at 1, counted in characters.
+    pub column: u32,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic followed by the source line it points at,
+    /// with the span underlined:
+    ///
+    /// ```text
+    /// [line 1] Error at 'b': Expect ';' after value.
+    ///  1 | print a b;
+    ///    |         ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        format!("{self}\n{}", excerpt(source, self.span))
+    }
+}
+
+/// Source line containing start of `span`, with the span underlined
+/// by carets, or nothing if `span` doesn't fit in `source`.
+pub fn excerpt(source: &str, span: Span) -> String {
+    let (Some(before), Some(after)) =
+        (source.get(..span.start), source.get(span.start..))
+    else {
+        return String::new();
+    };
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line_end = after.find('\n').map_or(source.len(), |i| span.start + i);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+
+    // keep tabs, so the carets line up with the excerpt
+    let padding: String = source[line_start..span.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let end = span.end.clamp(span.start, line_end);
+    let width = source
+        .get(span.start..end)
+        .map_or(0, |text| text.trim_end().chars().count())
+        .max(1);
+
+    // might differ from reported line for tokens spanning multiple lines
+    let line_number = before.matches('\n').count() + 1;
+    let gutter = line_number.to_string().len();
+    format!(
+        " {line_number:gutter$} | {line}\n {:gutter$} | {padding}{}\n",
+        "",
+        "^".repeat(width),
+    )
+}
+
+// same format as clox's errors
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.severity)?;
+        if let Some(at) = &self.at {
+            write!(f, " at {at}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
@@ -0,0 +1,164 @@
+//! Values passed between the vm and its host.
+//!
+//! Inside the vm, objects live on a garbage collected heap and are only
+//! valid while something the vm knows about references them, so the host
+//! never gets to hold them directly. Strings are copied out, and every
+//! other object is handed out as an [`Object`], which keeps it alive.
+
+use std::{
+    fmt,
+    rc::{Rc, Weak},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{value, vm::RuntimeError, Vm};
+
+/// Function the host makes callable from scripts, see
+/// [`Vm::define_native`].
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError>;
+
+/// Value as the host sees it, see the [module docs](self).
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Function, class, instance or anything else that isn't a string.
+    Object(Object),
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Self::Number(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl From<Object> for Value {
+    fn from(object: Object) -> Self {
+        Self::Object(object)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{s}"),
+            Self::Object(object) => write!(f, "{object}"),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "{s:?}"),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+/// Object on the vm's heap, kept alive for as long as any handle to it
+/// exists. Can only be passed back to the vm it came from.
+#[derive(Clone)]
+pub struct Object(Rc<Root>);
+
+impl Object {
+    pub(crate) fn vm(&self) -> VmId {
+        self.0.vm
+    }
+
+    pub(crate) fn value(&self) -> value::Value {
+        self.0.value
+    }
+}
+
+/// Same object in the same vm.
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.vm == other.0.vm && self.0.value == other.0.value
+    }
+}
+
+/// Prints the same as `print` in a script would.
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.display)
+    }
+}
+
+impl fmt::Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// Tells vms apart, so that an [`Object`] can't be passed to another one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VmId(u64);
+
+impl VmId {
+    pub(crate) fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct Root {
+    vm: VmId,
+    value: value::Value,
+    // printed form, as the object may be gone by the time it's printed,
+    // if the vm is dropped first
+    display: String,
+}
+
+/// Objects the host holds handles to, marked as roots on every collection.
+#[derive(Default)]
+pub(crate) struct Roots {
+    roots: Vec<Weak<Root>>,
+    // live roots after the last cleanup
+    live: usize,
+}
+
+impl Roots {
+    pub(crate) fn root(&mut self, vm: VmId, value: value::Value) -> Object {
+        // drop the dead ones every now and then, so handles that come and go
+        // without the vm ever collecting don't pile up
+        if self.roots.len() >= 2 * self.live.max(8) {
+            self.roots.retain(|root| root.strong_count() > 0);
+            self.live = self.roots.len();
+        }
+        let display = value.to_string();
+        let root = Rc::new(Root { vm, value, display });
+        self.roots.push(Rc::downgrade(&root));
+        Object(root)
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = value::Value> + '_ {
+        self.roots
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|root| root.value)
+    }
+}
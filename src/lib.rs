@@ -0,0 +1,24 @@
+mod chunk;
+mod common;
+mod compiler;
+mod debug;
+pub mod diagnostic;
+pub mod host;
+mod memory;
+mod object;
+mod scanner;
+mod script;
+mod table;
+mod value;
+mod verify;
+pub mod vm;
+
+pub use chunk::{BytecodeError, BYTECODE_VERSION, MAGIC};
+pub use compiler::CompileError;
+pub use diagnostic::{Diagnostic, Severity};
+pub use host::{NativeFn, Object, Value};
+pub use scanner::{is_complete, Span};
+pub use script::{compile, Script};
+pub use vm::{
+    Error, RuntimeError, RuntimeErrorKind, StackSize, TraceFrame, Vm,
+};
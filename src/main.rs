@@ -1,63 +1,259 @@
-mod chunk;
-mod common;
-mod compiler;
-mod debug;
-mod scanner;
-mod table;
-mod value;
-mod vm;
-
 use std::{
     env, fs,
-    io::{stdin, stdout, Write},
+    io::{self, stdin, stdout, Read},
+    path::{Path, PathBuf},
     process::ExitCode,
+    sync::OnceLock,
 };
 
-use vm::{Error, Vm};
+use clox::{Error, RuntimeError, RuntimeErrorKind, Value, Vm, MAGIC};
+
+mod repl;
+
+const USAGE: &str = "\
+Usage: clox [options] [path | -e code | -] [args...]
+       clox compile (path | -) [-o output]
+
+Runs script from `path`, `code` or stdin (`-`), or starts a repl if there's
+none. Arguments after the script are available to it through `argc()`
+and `argv(index)`.
+
+`compile` writes bytecode of the script to `output`, by default `path`
+with `.loxc` extension. Compiled scripts run the same way as source ones.
+
+Options:
+  --disassemble  print bytecode of the script instead of running it
+  --trace        print each instruction to stderr as it runs
+";
+
+enum Input {
+    Repl,
+    File(String),
+    Code(String),
+    Stdin,
+}
+
+struct Options {
+    input: Input,
+    disassemble: bool,
+    trace: bool,
+    // set for `compile`, which writes bytecode there instead of running
+    compile_to: Option<PathBuf>,
+    script_args: Vec<String>,
+}
+
+enum Program {
+    Source(String),
+    Bytecode(Vec<u8>),
+}
 
-fn repl() {
-    let mut vm = Vm::default();
-    loop {
-        print!("> ");
-        stdout().flush().unwrap();
-        let line = {
-            let mut buf = String::new();
-            if let Err(_) = stdin().read_line(&mut buf) {
-                return;
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        input: Input::Repl,
+        disassemble: false,
+        trace: false,
+        compile_to: None,
+        script_args: vec![],
+    };
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "compile").is_some() {
+        return parse_compile_args(args, options);
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disassemble" => options.disassemble = true,
+            "--trace" => options.trace = true,
+            "-e" => {
+                let code = args.next().ok_or("Missing code after '-e'.")?;
+                options.input = Input::Code(code);
+                break;
+            }
+            "-" => {
+                options.input = Input::Stdin;
+                break;
+            }
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option '{arg}'."));
             }
-            buf
-        };
-        let _ = vm.interpret(&line);
+            _ => {
+                options.input = Input::File(arg);
+                break;
+            }
+        }
     }
+    // everything after the script belongs to it
+    options.script_args = args.collect();
+    if options.disassemble && matches!(options.input, Input::Repl) {
+        return Err("Nothing to disassemble.".to_string());
+    }
+    Ok(options)
 }
 
-fn run_file(path: String) -> ExitCode {
-    let source = match fs::read_to_string(&path) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Couldn't read file {path}: {e}");
-            return ExitCode::from(74);
+fn parse_compile_args(
+    mut args: impl Iterator<Item = String>,
+    mut options: Options,
+) -> Result<Options, String> {
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                let path = args.next().ok_or("Missing path after '-o'.")?;
+                output = Some(PathBuf::from(path));
+            }
+            _ if !matches!(options.input, Input::Repl) => {
+                return Err(format!("Unexpected argument '{arg}'."));
+            }
+            "-" => options.input = Input::Stdin,
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option '{arg}'."));
+            }
+            _ => options.input = Input::File(arg),
+        }
+    }
+    let output = match (&options.input, output) {
+        (Input::Repl, _) => return Err("Nothing to compile.".to_string()),
+        (_, Some(output)) => output,
+        (Input::File(path), None) => Path::new(path).with_extension("loxc"),
+        (_, None) => return Err("Missing output path, see '-o'.".to_string()),
+    };
+    options.compile_to = Some(output);
+    Ok(options)
+}
+
+static SCRIPT_ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+fn script_args() -> &'static [String] {
+    SCRIPT_ARGS.get().map_or(&[], Vec::as_slice)
+}
+
+fn argc_native(_vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::from(script_args().len() as f64))
+}
+
+fn argv_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::Number(index) = args[0] else {
+        return Err(RuntimeError::new(
+            RuntimeErrorKind::Native,
+            "Argument index must be a number.",
+        ));
+    };
+    let arg = (index.fract() == 0.0 && index >= 0.0)
+        .then(|| script_args().get(index as usize))
+        .flatten();
+    Ok(arg.map_or(Value::Nil, |arg| Value::from(arg.as_str())))
+}
+
+// compiled scripts are told apart by their header, not by extension
+fn read_program(input: &Input) -> io::Result<Program> {
+    let bytes = match input {
+        Input::Repl => unreachable!(),
+        Input::File(path) => fs::read(path)?,
+        Input::Code(code) => code.clone().into_bytes(),
+        Input::Stdin => {
+            let mut bytes = vec![];
+            stdin().read_to_end(&mut bytes)?;
+            bytes
+        }
+    };
+    if bytes.starts_with(MAGIC) {
+        return Ok(Program::Bytecode(bytes));
+    }
+    String::from_utf8(bytes)
+        .map(Program::Source)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn run(vm: &mut Vm, program: &Program, disassemble: bool) -> ExitCode {
+    if disassemble {
+        vm.set_trace_output(stdout());
+    }
+    // bytecode has no source, so its errors come without excerpts
+    let (result, source) = match program {
+        Program::Source(source) if disassemble => (
+            vm.disassemble(source).map_err(Error::Compile),
+            source.as_str(),
+        ),
+        Program::Source(source) => (vm.interpret(source), source.as_str()),
+        Program::Bytecode(bytes) if disassemble => {
+            (vm.disassemble_bytecode(bytes).map_err(Error::Bytecode), "")
         }
+        Program::Bytecode(bytes) => (vm.interpret_bytecode(bytes), ""),
     };
-    let mut vm = Vm::default();
-    match vm.interpret(&source) {
+    match result {
         Ok(()) => ExitCode::SUCCESS,
-        Err(Error::Compile(_)) => ExitCode::from(65),
-        Err(Error::Runtime) => ExitCode::from(70),
+        Err(Error::Compile(error)) => {
+            eprint!("{}", error.render(source));
+            ExitCode::from(65)
+        }
+        Err(Error::Bytecode(error)) => {
+            eprintln!("{error}");
+            ExitCode::from(65)
+        }
+        Err(Error::Runtime(error)) => {
+            eprint!("{}", error.render(source));
+            ExitCode::from(70)
+        }
+    }
+}
+
+fn compile(vm: &mut Vm, program: &Program, output: &Path) -> ExitCode {
+    let Program::Source(source) = program else {
+        eprintln!("Script is already compiled.");
+        return ExitCode::from(65);
+    };
+    let bytes = match vm.compile_bytecode(source) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprint!("{}", error.render(source));
+            return ExitCode::from(65);
+        }
+    };
+    match fs::write(output, bytes) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Couldn't write file {}: {e}", output.display());
+            ExitCode::from(73)
+        }
     }
 }
 
 fn main() -> ExitCode {
-    let mut args = env::args().skip(1);
-    let arg1 = args.next();
-    let more = args.next().is_some();
-    match (arg1, more) {
-        (None, _) => repl(),
-        (Some(file), false) => return run_file(file),
-        (Some(_), true) => {
-            eprintln!("Usage: clox [path]");
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            eprint!("{USAGE}");
             return ExitCode::from(64);
         }
+    };
+    SCRIPT_ARGS.get_or_init(|| options.script_args);
+
+    let trace = options.trace;
+    let new_vm = move || {
+        let mut vm = Vm::default();
+        vm.set_trace_execution(trace);
+        vm.define_native("argc", 0, argc_native);
+        vm.define_native("argv", 1, argv_native);
+        vm
+    };
+
+    if let Input::Repl = options.input {
+        repl::run(&new_vm);
+        return ExitCode::SUCCESS;
+    }
+    match read_program(&options.input) {
+        Ok(program) => match &options.compile_to {
+            Some(output) => compile(&mut new_vm(), &program, output),
+            None => run(&mut new_vm(), &program, options.disassemble),
+        },
+        Err(e) => {
+            match &options.input {
+                Input::File(path) => {
+                    eprintln!("Couldn't read file {path}: {e}")
+                }
+                _ => eprintln!("Couldn't read script from stdin: {e}"),
+            }
+            ExitCode::from(74)
+        }
     }
-    ExitCode::SUCCESS
 }
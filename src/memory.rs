@@ -0,0 +1,357 @@
+use std::{cell::Cell, fmt, mem::size_of, ops::Deref, ptr::NonNull};
+
+use crate::{
+    common::{DEBUG_LOG_GC, GC_HEAP_GROW_FACTOR},
+    object::{
+        ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance,
+        ObjNative, ObjString, ObjUpvalue,
+    },
+    table::Table,
+    value::{hash, Unpacked, Value},
+};
+
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjKind {
+    String,
+    Function,
+    Native,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+}
+
+// common part of every heap allocation, objects link into an intrusive list
+// of everything the heap owns
+#[repr(C)]
+struct Header {
+    kind: ObjKind,
+    is_marked: Cell<bool>,
+    next: Cell<Option<NonNull<Header>>>,
+}
+
+// `repr(C)` so pointer to the box is also a valid pointer to its header
+#[repr(C)]
+struct GcBox<T> {
+    header: Header,
+    value: T,
+}
+
+/// Handle to an object owned by the [`Heap`].
+///
+/// It stays valid as long as object is reachable from the vm's roots,
+/// handles to unreachable objects dangle after the next collection.
+pub struct Gc<T> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> Gc<T> {
+    pub fn ptr_eq(a: Self, b: Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    fn header(self) -> NonNull<Header> {
+        self.ptr.cast()
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub fn into_raw(self) -> *const () {
+        self.ptr.as_ptr() as *const ()
+    }
+
+    /// # Safety
+    /// `ptr` has to come from [`Gc::into_raw`] of a live object of type `T`.
+    #[cfg(feature = "nan-boxing")]
+    pub unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self {
+            ptr: NonNull::new_unchecked(ptr as *mut GcBox<T>),
+        }
+    }
+
+    fn is_marked(self) -> bool {
+        // SAFETY: see `Gc::deref`
+        unsafe { self.header().as_ref() }.is_marked.get()
+    }
+}
+
+/// Kind of object behind a pointer from [`Gc::into_raw`].
+///
+/// # Safety
+/// `ptr` has to point to a live object.
+#[cfg(feature = "nan-boxing")]
+pub unsafe fn raw_kind(ptr: *const ()) -> ObjKind {
+    (*(ptr as *const Header)).kind
+}
+
+impl<T> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: objects only get freed by the collector, which only frees
+        // ones unreachable from the roots, so live handles point to live ones
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        T::fmt(self, f)
+    }
+}
+
+impl<T> fmt::Pointer for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&self.ptr, f)
+    }
+}
+
+pub trait Object: Sized {
+    const KIND: ObjKind;
+
+    /// Marks every object this one references.
+    fn trace(&self, _heap: &mut Heap) {}
+
+    /// Bytes owned by the object outside of its allocation,
+    /// counted towards the next collection. Whatever it grows by after
+    /// being allocated has to be counted with [`Heap::grew`].
+    fn extra_size(&self) -> usize {
+        0
+    }
+}
+
+pub struct Heap {
+    objects: Option<NonNull<Header>>,
+    gray_stack: Vec<NonNull<Header>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    grow_factor: f64,
+    // every live string, only weakly referenced, so strings that are
+    // otherwise unreachable get collected and removed from here
+    strings: Table,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: None,
+            gray_stack: vec![],
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            grow_factor: GC_HEAP_GROW_FACTOR,
+            strings: Table::default(),
+        }
+    }
+}
+
+impl Heap {
+    /// Moves `value` onto the heap. Never triggers a collection, it's up to
+    /// the caller to check [`Heap::should_collect`] beforehand.
+    pub fn alloc<T: Object>(&mut self, value: T) -> Gc<T> {
+        let size = size_of::<GcBox<T>>() + value.extra_size();
+        self.bytes_allocated += size;
+        let boxed = Box::new(GcBox {
+            header: Header {
+                kind: T::KIND,
+                is_marked: Cell::new(false),
+                next: Cell::new(self.objects),
+            },
+            value,
+        });
+        let gc = Gc {
+            ptr: NonNull::from(Box::leak(boxed)),
+        };
+        self.objects = Some(gc.header());
+        if DEBUG_LOG_GC {
+            println!("{gc:p} allocate {size} for {:?}", T::KIND);
+        }
+        gc
+    }
+
+    /// Returns the one string object with given contents, allocating it
+    /// if there isn't one yet. Same as [`Heap::alloc`], it never collects.
+    pub fn intern(&mut self, string: String) -> Gc<ObjString> {
+        let string = ObjString::new(string);
+        if let Some(interned) =
+            self.strings.find_string(string.as_str(), string.hash())
+        {
+            return interned;
+        }
+        let interned = self.alloc(string);
+        self.strings.set(interned, Value::NIL);
+        interned
+    }
+
+    /// Returns already interned string with given contents, if any.
+    pub fn find_interned(&self, string: &str) -> Option<Gc<ObjString>> {
+        self.strings.find_string(string, hash(string.as_bytes()))
+    }
+
+    /// How much the heap may grow after a collection before the next one,
+    /// as a multiple of what survived the last one.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is less than 1.
+    pub fn set_grow_factor(&mut self, factor: f64) {
+        assert!(factor >= 1.0, "heap grow factor must be at least 1");
+        self.grow_factor = factor;
+    }
+
+    /// Counts `bytes` some object took on after it was allocated, e.g. when
+    /// its table grew, see [`Object::extra_size`].
+    pub fn grew(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        match value.unpack() {
+            Unpacked::Nil | Unpacked::Bool(_) | Unpacked::Number(_) => (),
+            Unpacked::String(s) => self.mark_object(s),
+            Unpacked::Function(f) => self.mark_object(f),
+            Unpacked::Native(n) => self.mark_object(n),
+            Unpacked::Closure(c) => self.mark_object(c),
+            Unpacked::Class(c) => self.mark_object(c),
+            Unpacked::Instance(i) => self.mark_object(i),
+            Unpacked::BoundMethod(b) => self.mark_object(b),
+        }
+    }
+
+    pub fn mark_object<T>(&mut self, object: Gc<T>) {
+        let header = object.header();
+        // SAFETY: see `Gc::deref`
+        let is_marked = unsafe { &header.as_ref().is_marked };
+        if is_marked.get() {
+            return;
+        }
+        if DEBUG_LOG_GC {
+            println!("{object:p} mark");
+        }
+        is_marked.set(true);
+        self.gray_stack.push(header);
+    }
+
+    /// Frees everything not reachable from objects marked so far,
+    /// so all roots have to be marked before calling this.
+    pub fn collect(&mut self) {
+        let before = self.bytes_allocated;
+
+        while let Some(object) = self.gray_stack.pop() {
+            self.blacken(object);
+        }
+        self.strings.retain(|string| string.is_marked());
+        self.sweep();
+
+        let next_gc = self.bytes_allocated as f64 * self.grow_factor;
+        self.next_gc = (next_gc as usize).max(GC_INITIAL_THRESHOLD);
+
+        if DEBUG_LOG_GC {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {before} to {}) next at {}",
+                before - self.bytes_allocated,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn blacken(&mut self, object: NonNull<Header>) {
+        if DEBUG_LOG_GC {
+            println!("{object:p} blacken");
+        }
+        // SAFETY: header's kind always matches type of the box
+        unsafe {
+            match object.as_ref().kind {
+                ObjKind::String => self.blacken_as::<ObjString>(object),
+                ObjKind::Function => self.blacken_as::<ObjFunction>(object),
+                ObjKind::Native => self.blacken_as::<ObjNative>(object),
+                ObjKind::Closure => self.blacken_as::<ObjClosure>(object),
+                ObjKind::Upvalue => self.blacken_as::<ObjUpvalue>(object),
+                ObjKind::Class => self.blacken_as::<ObjClass>(object),
+                ObjKind::Instance => self.blacken_as::<ObjInstance>(object),
+                ObjKind::BoundMethod => {
+                    self.blacken_as::<ObjBoundMethod>(object)
+                }
+            }
+        }
+    }
+
+    unsafe fn blacken_as<T: Object>(&mut self, object: NonNull<Header>) {
+        let object = object.cast::<GcBox<T>>();
+        object.as_ref().value.trace(self);
+    }
+
+    fn sweep(&mut self) {
+        let mut previous: Option<NonNull<Header>> = None;
+        let mut current = self.objects;
+        while let Some(object) = current {
+            // SAFETY: everything on the list is alive until freed below
+            let header = unsafe { object.as_ref() };
+            current = header.next.get();
+            if header.is_marked.get() {
+                header.is_marked.set(false);
+                previous = Some(object);
+                continue;
+            }
+            match previous {
+                // SAFETY: same as above
+                Some(previous) => {
+                    unsafe { previous.as_ref() }.next.set(current)
+                }
+                None => self.objects = current,
+            }
+            self.free(object);
+        }
+    }
+
+    fn free(&mut self, object: NonNull<Header>) {
+        if DEBUG_LOG_GC {
+            println!("{object:p} free");
+        }
+        // SAFETY: header's kind always matches type of the box,
+        // and object was just unlinked, so nothing else will free it again
+        unsafe {
+            match object.as_ref().kind {
+                ObjKind::String => self.free_as::<ObjString>(object),
+                ObjKind::Function => self.free_as::<ObjFunction>(object),
+                ObjKind::Native => self.free_as::<ObjNative>(object),
+                ObjKind::Closure => self.free_as::<ObjClosure>(object),
+                ObjKind::Upvalue => self.free_as::<ObjUpvalue>(object),
+                ObjKind::Class => self.free_as::<ObjClass>(object),
+                ObjKind::Instance => self.free_as::<ObjInstance>(object),
+                ObjKind::BoundMethod => self.free_as::<ObjBoundMethod>(object),
+            }
+        }
+    }
+
+    unsafe fn free_as<T: Object>(&mut self, object: NonNull<Header>) {
+        let object = Box::from_raw(object.cast::<GcBox<T>>().as_ptr());
+        let size = size_of::<GcBox<T>>() + object.value.extra_size();
+        self.bytes_allocated = self.bytes_allocated.saturating_sub(size);
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        while let Some(object) = self.objects {
+            // SAFETY: every object on the list is alive
+            self.objects = unsafe { object.as_ref() }.next.get();
+            self.free(object);
+        }
+    }
+}
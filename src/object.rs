@@ -1 +1,182 @@
-// the stuff is in value.rs, ya dingus
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    mem::size_of,
+};
+
+use crate::{
+    chunk::Chunk,
+    host::NativeFn,
+    memory::{Gc, Heap, ObjKind, Object},
+    table::Table,
+    value::{hash, Value},
+};
+
+pub struct ObjString {
+    string: String,
+    hash: u32,
+}
+
+impl ObjString {
+    pub fn new(string: String) -> Self {
+        let hash = hash(string.as_bytes());
+        Self { string, hash }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+}
+
+impl fmt::Display for ObjString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.string)
+    }
+}
+
+impl Object for ObjString {
+    const KIND: ObjKind = ObjKind::String;
+
+    fn extra_size(&self) -> usize {
+        self.string.capacity()
+    }
+}
+
+#[derive(Default)]
+pub struct ObjFunction {
+    pub arity: u8,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // `None` for top-level script
+    pub name: Option<String>,
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl Object for ObjFunction {
+    const KIND: ObjKind = ObjKind::Function;
+
+    fn trace(&self, heap: &mut Heap) {
+        for &constant in self.chunk.constants() {
+            heap.mark_value(constant);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.chunk.allocated_size()
+            + self.name.as_ref().map_or(0, String::capacity)
+    }
+}
+
+pub struct ObjNative {
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl Object for ObjNative {
+    const KIND: ObjKind = ObjKind::Native;
+}
+
+pub struct ObjClosure {
+    pub function: Gc<ObjFunction>,
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
+}
+
+impl Object for ObjClosure {
+    const KIND: ObjKind = ObjKind::Closure;
+
+    fn extra_size(&self) -> usize {
+        self.upvalues.capacity() * size_of::<Gc<ObjUpvalue>>()
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.function);
+        for &upvalue in &self.upvalues {
+            heap.mark_object(upvalue);
+        }
+    }
+}
+
+pub struct ObjUpvalue {
+    // stack slot of the captured variable, valid only while it's open
+    pub location: usize,
+    // `Some` once the variable went out of scope and was moved here
+    pub closed: Cell<Option<Value>>,
+}
+
+impl Object for ObjUpvalue {
+    const KIND: ObjKind = ObjKind::Upvalue;
+
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(value) = self.closed.get() {
+            heap.mark_value(value);
+        }
+    }
+}
+
+pub struct ObjClass {
+    pub name: Gc<ObjString>,
+    pub methods: RefCell<Table>,
+}
+
+impl Object for ObjClass {
+    const KIND: ObjKind = ObjKind::Class;
+
+    fn extra_size(&self) -> usize {
+        self.methods.borrow().allocated_size()
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.name);
+        for (&name, &method) in self.methods.borrow().iter() {
+            heap.mark_object(name);
+            heap.mark_value(method);
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub class: Gc<ObjClass>,
+    pub fields: RefCell<Table>,
+}
+
+impl Object for ObjInstance {
+    const KIND: ObjKind = ObjKind::Instance;
+
+    fn extra_size(&self) -> usize {
+        self.fields.borrow().allocated_size()
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.class);
+        for (&name, &value) in self.fields.borrow().iter() {
+            heap.mark_object(name);
+            heap.mark_value(value);
+        }
+    }
+}
+
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Gc<ObjClosure>,
+}
+
+impl Object for ObjBoundMethod {
+    const KIND: ObjKind = ObjKind::BoundMethod;
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(self.receiver);
+        heap.mark_object(self.method);
+    }
+}
@@ -0,0 +1,134 @@
+//! Interactive prompt of the `clox` binary.
+
+mod editor;
+mod terminal;
+
+use std::{env, fs, io, path::PathBuf, time::Instant};
+
+use clox::{is_complete, Error, Value, Vm};
+
+use self::editor::{Editor, ReadLine};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ". ";
+
+const HELP: &str = "\
+:globals        list global variables
+:dis <expr>     print bytecode of expression, without running it
+:trace on|off   print each instruction as it runs
+:load <path>    run script from file
+:reset          start over with a fresh vm
+:time <stmt>    run statement and print how long it took
+:help           show this
+";
+
+fn history_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".clox_history"))
+}
+
+// `new_vm` creates vm to start with, and again on each `:reset`
+pub fn run(new_vm: &dyn Fn() -> Vm) {
+    let mut vm = new_vm();
+    let mut editor = Editor::new(history_path());
+    let mut source = String::new();
+    loop {
+        let prompt = match source.is_empty() {
+            true => PROMPT,
+            false => CONTINUATION_PROMPT,
+        };
+        let line = match editor.read_line(prompt) {
+            Ok(ReadLine::Line(line)) => line,
+            Ok(ReadLine::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Ok(ReadLine::Eof) => break,
+            Err(e) => {
+                eprintln!("Couldn't read input: {e}");
+                break;
+            }
+        };
+        if source.is_empty() && line.starts_with(':') {
+            command(&mut vm, new_vm, &line);
+            continue;
+        }
+        source.push_str(&line);
+        source.push('\n');
+        if !is_complete(&source) {
+            continue;
+        }
+        interpret_line(&mut vm, &source);
+        source.clear();
+    }
+}
+
+// prints value of a lone expression, like `1 + 2`, unless it's nil
+fn interpret_line(vm: &mut Vm, source: &str) {
+    match vm.interpret_repl(source) {
+        Ok(Value::Nil) => (),
+        Ok(value) => println!("{value:?}"),
+        Err(error) => report(error, source),
+    }
+}
+
+fn interpret(vm: &mut Vm, source: &str) {
+    if let Err(error) = vm.interpret(source) {
+        report(error, source);
+    }
+}
+
+fn report(error: Error, source: &str) {
+    match error {
+        Error::Compile(error) => eprint!("{}", error.render(source)),
+        Error::Bytecode(error) => eprintln!("{error}"),
+        Error::Runtime(error) => eprint!("{}", error.render(source)),
+    }
+}
+
+// meta-commands, for poking at the vm instead of running code
+fn command(vm: &mut Vm, new_vm: &dyn Fn() -> Vm, line: &str) {
+    let (name, arg) = match line.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (line, ""),
+    };
+    match (name, arg) {
+        (":globals", "") => {
+            let mut globals: Vec<_> = vm.globals().collect();
+            globals.sort_by_key(|&(name, _)| name);
+            for (name, value) in globals {
+                println!("{name} = {value:?}");
+            }
+        }
+        (":dis", expression) if !expression.is_empty() => {
+            // the bytecode is what was asked for here, not a trace, so it
+            // goes to stdout like with `--disassemble`
+            vm.set_trace_output(io::stdout());
+            let result = vm.disassemble_expression(expression);
+            vm.set_trace_output(io::stderr());
+            if let Err(error) = result {
+                eprint!("{}", error.render(expression));
+            }
+        }
+        (":trace", "on" | "off") => {
+            if !cfg!(feature = "trace") {
+                eprintln!(
+                    "Tracing isn't compiled in, see the 'trace' feature."
+                );
+            }
+            vm.set_trace_execution(arg == "on");
+        }
+        (":load", path) if !path.is_empty() => match fs::read_to_string(path) {
+            Ok(source) => interpret(vm, &source),
+            Err(e) => eprintln!("Couldn't read file {path}: {e}"),
+        },
+        (":reset", "") => *vm = new_vm(),
+        (":time", statement) if !statement.is_empty() => {
+            let start = Instant::now();
+            interpret_line(vm, statement);
+            println!("took {:?}", start.elapsed());
+        }
+        (":help", "") => print!("{HELP}"),
+        _ => eprintln!("Unknown command '{line}', see ':help'."),
+    }
+}
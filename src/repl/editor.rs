@@ -0,0 +1,281 @@
+//! Single-line editor with history, for terminals that understand
+//! the usual vt100 escape sequences.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, stdin, stdout, BufRead, IsTerminal, Read, Write},
+    path::PathBuf,
+};
+
+use super::terminal::RawMode;
+
+const HISTORY_MAX: usize = 1000;
+
+pub enum ReadLine {
+    Line(String),
+    /// Ctrl-C, line is thrown away.
+    Interrupted,
+    /// Ctrl-D on an empty line, or end of piped input.
+    Eof,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Ctrl(u8),
+    Unknown,
+    // terminal went away
+    Eof,
+}
+
+pub struct Editor {
+    history: Vec<String>,
+    // lines are appended as they're entered, so history survives crashes
+    history_path: Option<PathBuf>,
+    interactive: bool,
+}
+
+impl Editor {
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = history_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|history| history.lines().map(String::from).collect())
+            .unwrap_or_default();
+        let excess = history.len().saturating_sub(HISTORY_MAX);
+        history.drain(..excess);
+        Self {
+            history,
+            history_path,
+            interactive: stdin().is_terminal() && stdout().is_terminal(),
+        }
+    }
+
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        let raw_mode = match self.interactive {
+            true => RawMode::enable().ok(),
+            false => None,
+        };
+        let Some(_raw_mode) = raw_mode else {
+            return read_plain_line(prompt);
+        };
+        let line = self.edit(prompt)?;
+        // back to a fresh line, as enter didn't echo one
+        println!();
+        if let ReadLine::Line(line) = &line {
+            self.add_history(line);
+        }
+        Ok(line)
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty()
+            || self.history.last().is_some_and(|last| last == line)
+        {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > HISTORY_MAX {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_path {
+            // history is a nicety, not worth failing over
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+        }
+    }
+
+    fn edit(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        let mut line: Vec<char> = vec![];
+        let mut cursor = 0;
+        // position in history, `history.len()` is the line being edited,
+        // which gets stashed while browsing
+        let mut history_index = self.history.len();
+        let mut stashed = vec![];
+
+        loop {
+            refresh(prompt, &line, cursor)?;
+            match read_key()? {
+                Key::Enter => break,
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Backspace | Key::Ctrl(b'H') if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Ctrl(b'D') if line.is_empty() => return Ok(ReadLine::Eof),
+                Key::Eof => return Ok(ReadLine::Eof),
+                Key::Delete | Key::Ctrl(b'D') if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Ctrl(b'C') => {
+                    print!("^C");
+                    return Ok(ReadLine::Interrupted);
+                }
+                Key::Left | Key::Ctrl(b'B') => {
+                    cursor = cursor.saturating_sub(1)
+                }
+                Key::Right | Key::Ctrl(b'F') => {
+                    cursor = (cursor + 1).min(line.len())
+                }
+                Key::Home | Key::Ctrl(b'A') => cursor = 0,
+                Key::End | Key::Ctrl(b'E') => cursor = line.len(),
+                Key::Ctrl(b'K') => line.truncate(cursor),
+                Key::Ctrl(b'U') => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::Ctrl(b'W') => {
+                    let mut start = cursor;
+                    while start > 0 && line[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    while start > 0 && !line[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    line.drain(start..cursor);
+                    cursor = start;
+                }
+                Key::Ctrl(b'L') => print!("\x1b[H\x1b[2J"),
+                Key::Up | Key::Ctrl(b'P') if history_index > 0 => {
+                    if history_index == self.history.len() {
+                        stashed = line;
+                    }
+                    history_index -= 1;
+                    line = self.history[history_index].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down | Key::Ctrl(b'N')
+                    if history_index < self.history.len() =>
+                {
+                    history_index += 1;
+                    line = match self.history.get(history_index) {
+                        Some(entry) => entry.chars().collect(),
+                        None => std::mem::take(&mut stashed),
+                    };
+                    cursor = line.len();
+                }
+                _ => {}
+            }
+        }
+        Ok(ReadLine::Line(line.into_iter().collect()))
+    }
+}
+
+// for pipes and dumb terminals, where the terminal does the editing
+fn read_plain_line(prompt: &str) -> io::Result<ReadLine> {
+    print!("{prompt}");
+    stdout().flush()?;
+    let mut line = String::new();
+    if stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(ReadLine::Eof);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(ReadLine::Line(line))
+}
+
+// redraws the whole line, long lines that wrap aren't handled
+fn refresh(prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let text: String = line.iter().collect();
+    let column = prompt.chars().count() + cursor;
+    let mut out = stdout().lock();
+    write!(out, "\r{prompt}{text}\x1b[K\r")?;
+    if column > 0 {
+        write!(out, "\x1b[{column}C")?;
+    }
+    out.flush()
+}
+
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stdin().lock().read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn read_key() -> io::Result<Key> {
+    let Some(byte) = read_byte()? else {
+        return Ok(Key::Eof);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        127 => Key::Backspace,
+        0x1b => read_escape()?,
+        1..=26 => Key::Ctrl(b'A' + byte - 1),
+        0..=31 => Key::Unknown,
+        _ => read_char(byte)?,
+    };
+    Ok(key)
+}
+
+// rest of `ESC [ ...` or `ESC O ...` sequence
+fn read_escape() -> io::Result<Key> {
+    let (Some(kind), Some(code)) = (read_byte()?, read_byte()?) else {
+        return Ok(Key::Unknown);
+    };
+    let key = match (kind, code) {
+        (b'[' | b'O', b'A') => Key::Up,
+        (b'[' | b'O', b'B') => Key::Down,
+        (b'[' | b'O', b'C') => Key::Right,
+        (b'[' | b'O', b'D') => Key::Left,
+        (b'[' | b'O', b'H') => Key::Home,
+        (b'[' | b'O', b'F') => Key::End,
+        (b'[', b'0'..=b'9') => {
+            // `ESC [ n ~`, anything with modifiers is ignored
+            let mut last = code;
+            let mut number = vec![code];
+            while let Some(byte) = read_byte()? {
+                last = byte;
+                if !byte.is_ascii_digit() {
+                    break;
+                }
+                number.push(byte);
+            }
+            match (&number[..], last) {
+                (b"1" | b"7", b'~') => Key::Home,
+                (b"4" | b"8", b'~') => Key::End,
+                (b"3", b'~') => Key::Delete,
+                _ => Key::Unknown,
+            }
+        }
+        _ => Key::Unknown,
+    };
+    Ok(key)
+}
+
+// decodes rest of utf-8 sequence starting with `first`
+fn read_char(first: u8) -> io::Result<Key> {
+    let len = match first.leading_ones() {
+        0 => 1,
+        n @ 2..=4 => n as usize,
+        _ => return Ok(Key::Unknown),
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match read_byte()? {
+            Some(byte) => bytes.push(byte),
+            None => return Ok(Key::Unknown),
+        }
+    }
+    let key = std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+        .map_or(Key::Unknown, Key::Char);
+    Ok(key)
+}
@@ -0,0 +1,124 @@
+//! Just enough of termios to read keys one at a time.
+//!
+//! Only on linux architectures whose `struct termios` layout was checked,
+//! elsewhere raw mode is unsupported and the prompt reads plain lines.
+
+use std::io;
+
+/// Puts the terminal into raw mode, restoring previous mode on drop.
+pub struct RawMode {
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    original: sys::Termios,
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let original = sys::get()?;
+        let mut raw = original;
+        // no echo, no line buffering, no signals from ctrl-c and ctrl-z,
+        // no ctrl-s/ctrl-q flow control and no cr-to-nl translation,
+        // output processing stays so `\n` still starts a new line
+        raw.c_lflag &= !(sys::ECHO | sys::ICANON | sys::ISIG | sys::IEXTEN);
+        raw.c_iflag &= !(sys::IXON | sys::ICRNL);
+        raw.c_cc[sys::VMIN] = 1;
+        raw.c_cc[sys::VTIME] = 0;
+        sys::set(&raw)?;
+        Ok(Self { original })
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = sys::set(&self.original);
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "raw mode is only supported on x86_64 and aarch64 linux",
+        ))
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use std::io;
+
+    const STDIN: i32 = 0;
+    const TCSAFLUSH: i32 = 2;
+
+    pub const ISIG: u32 = 0o1;
+    pub const ICANON: u32 = 0o2;
+    pub const ECHO: u32 = 0o10;
+    pub const IEXTEN: u32 = 0o100000;
+    pub const ICRNL: u32 = 0o400;
+    pub const IXON: u32 = 0o2000;
+    pub const VTIME: usize = 5;
+    pub const VMIN: usize = 6;
+
+    // `struct termios` from glibc and musl, same on x86_64 and aarch64,
+    // but not on every architecture, e.g. powerpc and mips differ
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct Termios {
+        pub c_iflag: u32,
+        pub c_oflag: u32,
+        pub c_cflag: u32,
+        pub c_lflag: u32,
+        pub c_line: u8,
+        pub c_cc: [u8; 32],
+        pub c_ispeed: u32,
+        pub c_ospeed: u32,
+    }
+
+    extern "C" {
+        fn tcgetattr(fd: i32, termios: *mut Termios) -> i32;
+        fn tcsetattr(fd: i32, action: i32, termios: *const Termios) -> i32;
+    }
+
+    pub fn get() -> io::Result<Termios> {
+        let mut termios = Termios {
+            c_iflag: 0,
+            c_oflag: 0,
+            c_cflag: 0,
+            c_lflag: 0,
+            c_line: 0,
+            c_cc: [0; 32],
+            c_ispeed: 0,
+            c_ospeed: 0,
+        };
+        // SAFETY: `Termios` matches libc's layout
+        if unsafe { tcgetattr(STDIN, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(termios)
+    }
+
+    pub fn set(termios: &Termios) -> io::Result<()> {
+        // SAFETY: same as in `get`
+        if unsafe { tcsetattr(STDIN, TCSAFLUSH, termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -51,11 +51,21 @@ pub enum TokenType {
     Eof,
 }
 
+/// Range of bytes in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Clone)]
 pub struct Token<'s> {
     pub r#type: TokenType,
     pub lexeme: &'s str,
     pub line: u32,
+    /// Column of the token's first character, starting at 1.
+    pub column: u32,
+    pub span: Span,
 }
 
 pub struct Scanner<'s> {
@@ -63,6 +73,10 @@ pub struct Scanner<'s> {
     start: usize,
     current: usize,
     line: u32,
+    // column of `start`
+    column: u32,
+    // column of `current`, kept up to date by `advance` and `new_line`
+    current_column: u32,
 }
 
 impl<'s> Scanner<'s> {
@@ -72,6 +86,8 @@ impl<'s> Scanner<'s> {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            current_column: 1,
         }
     }
 
@@ -91,6 +107,7 @@ impl<'s> Scanner<'s> {
     fn advance(&mut self) -> char {
         let c = self.peek();
         self.current += c.len_utf8();
+        self.current_column += 1;
         c
     }
 
@@ -110,17 +127,35 @@ impl<'s> Scanner<'s> {
             r#type,
             lexeme: &self.source[self.start..self.current],
             line: self.line,
+            column: self.column,
+            span: self.span(),
         }
     }
 
+    // span still points at the offending part of the source
     fn error_token(&self, message: &'static str) -> Token<'static> {
         Token {
             r#type: TokenType::Error,
             lexeme: message,
             line: self.line,
+            column: self.column,
+            span: self.span(),
         }
     }
 
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
+        }
+    }
+
+    // call after consuming the newline
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.current_column = 1;
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             match self.peek() {
@@ -128,16 +163,12 @@ impl<'s> Scanner<'s> {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -147,10 +178,9 @@ impl<'s> Scanner<'s> {
 
     fn string(&mut self) -> Token<'s> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
         if self.is_at_end() {
             return self.error_token("Unterminated string.");
@@ -208,6 +238,7 @@ impl<'s> Scanner<'s> {
     pub fn scan_token(&mut self) -> Token<'s> {
         self.skip_whitespace();
         self.start = self.current;
+        self.column = self.current_column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -277,3 +308,23 @@ impl<'s> Scanner<'s> {
 fn is_ident_start(c: char) -> bool {
     c.is_ascii_alphabetic() || c == '_'
 }
+
+/// Whether `source` doesn't end in the middle of a block, a parenthesized
+/// expression or a string. An interactive prompt can use it to tell if
+/// the next line continues the same input.
+pub fn is_complete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.r#type {
+            TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+            TokenType::Error if token.lexeme == "Unterminated string." => {
+                return false
+            }
+            TokenType::Eof => return depth <= 0,
+            _ => {}
+        }
+    }
+}
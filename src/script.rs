@@ -0,0 +1,52 @@
+//! Scripts compiled ahead of time, to run them later or more than once.
+
+use std::fmt;
+
+use crate::{
+    compiler::{self, Mode},
+    memory::{Gc, Heap},
+    object::ObjFunction,
+    vm::Error,
+};
+
+/// Compiled script, see [`compile`]. It isn't tied to any [`Vm`], the
+/// same one can be run by several of them with [`Vm::run`].
+///
+/// [`Vm`]: crate::Vm
+/// [`Vm::run`]: crate::Vm::run
+pub struct Script {
+    // owns the function and its constants, only there to free them on
+    // drop as nothing ever gets collected
+    _heap: Heap,
+    function: Gc<ObjFunction>,
+}
+
+/// Compiles `source` without running it.
+pub fn compile(source: &str) -> Result<Script, Error> {
+    let mut heap = Heap::default();
+    let function = compiler::compile(source, &mut heap, Mode::Script)?;
+    Ok(Script {
+        _heap: heap,
+        function,
+    })
+}
+
+impl Script {
+    /// Copy of the script's function allocated in `heap`. Like
+    /// [`Heap::alloc`], it never collects.
+    pub(crate) fn copy_to(&self, heap: &mut Heap) -> Gc<ObjFunction> {
+        let chunk = self.function.chunk.copy_to(heap);
+        heap.alloc(ObjFunction {
+            arity: self.function.arity,
+            upvalue_count: self.function.upvalue_count,
+            chunk,
+            name: self.function.name.clone(),
+        })
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script").finish_non_exhaustive()
+    }
+}
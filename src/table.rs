@@ -1,6 +1,6 @@
-use std::{iter::repeat_with, mem::size_of};
+use std::iter::repeat_with;
 
-use crate::value::{hash, ThinString, Value};
+use crate::{memory::Gc, object::ObjString, value::Value};
 
 #[derive(Default)]
 pub struct Table {
@@ -9,7 +9,7 @@ pub struct Table {
 }
 
 impl Table {
-    pub fn set(&mut self, key: String, value: Value) -> bool {
+    pub fn set(&mut self, key: Gc<ObjString>, value: Value) -> bool {
         if self.count * 4 >= self.capacity() * 3 {
             let new_capacity = if self.capacity() < 8 {
                 8
@@ -18,24 +18,22 @@ impl Table {
             };
             self.realloc(new_capacity);
         }
-        let entry = self.find_mut(&key);
+        let entry = self.find_mut(key);
         let is_new_key = !matches!(entry, Slot::Occupied(_));
         let was_tombstone = matches!(entry, Slot::Tombstone);
-        *entry = Slot::Occupied(OccupiedEntry {
-            key: ThinString::new(key),
-            value,
-        });
+        *entry = Slot::Occupied(OccupiedEntry { key, value });
         if is_new_key && !was_tombstone {
             self.count += 1;
         }
         is_new_key
     }
 
-    pub fn has(&self, key: &str) -> bool {
+    #[allow(dead_code)]
+    pub fn has(&self, key: Gc<ObjString>) -> bool {
         self.get(key).is_some()
     }
 
-    pub fn get(&self, key: &str) -> Option<&Value> {
+    pub fn get(&self, key: Gc<ObjString>) -> Option<&Value> {
         if self.count == 0 {
             return None;
         }
@@ -45,7 +43,7 @@ impl Table {
         }
     }
 
-    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+    pub fn get_mut(&mut self, key: Gc<ObjString>) -> Option<&mut Value> {
         if self.count == 0 {
             return None;
         }
@@ -55,7 +53,8 @@ impl Table {
         }
     }
 
-    pub fn delete(&mut self, key: &str) -> Option<Value> {
+    #[allow(dead_code)]
+    pub fn delete(&mut self, key: Gc<ObjString>) -> Option<Value> {
         if self.count == 0 {
             return None;
         }
@@ -77,15 +76,44 @@ impl Table {
     //     Entry { slot, key }
     // }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+    pub fn iter(&self) -> impl Iterator<Item = (&Gc<ObjString>, &Value)> {
         self.entries.iter().filter_map(|x| match x {
-            Slot::Occupied(OccupiedEntry { key, value }) => {
-                Some((key.as_str(), value))
-            }
+            Slot::Occupied(OccupiedEntry { key, value }) => Some((key, value)),
             Slot::Vacant | Slot::Tombstone => None,
         })
     }
 
+    // looks up key by its contents instead of identity, used for interning
+    pub fn find_string(&self, s: &str, hash: u32) -> Option<Gc<ObjString>> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut index = hash % self.capacity() as u32;
+        loop {
+            match &self.entries[index as usize] {
+                Slot::Occupied(OccupiedEntry { key, .. })
+                    if key.hash() == hash && key.as_str() == s =>
+                {
+                    return Some(*key);
+                }
+                Slot::Occupied(_) | Slot::Tombstone => {}
+                Slot::Vacant => return None,
+            }
+            index = (index + 1) % self.capacity() as u32;
+        }
+    }
+
+    // removes every entry whose key doesn't satisfy `f`
+    pub fn retain<F: FnMut(Gc<ObjString>) -> bool>(&mut self, mut f: F) {
+        for entry in self.entries.iter_mut() {
+            if let Slot::Occupied(OccupiedEntry { key, .. }) = entry {
+                if !f(*key) {
+                    *entry = Slot::Tombstone;
+                }
+            }
+        }
+    }
+
     fn realloc(&mut self, new_capacity: usize) {
         self.count = 0;
         let new_entries =
@@ -94,12 +122,17 @@ impl Table {
         for entry in old_entries.into_vec() {
             if let Slot::Occupied(entry) = entry {
                 self.count += 1;
-                let dest = self.find_mut(&entry.key);
+                let dest = self.find_mut(entry.key);
                 *dest = Slot::Occupied(entry);
             }
         }
     }
 
+    /// Bytes taken by its entries, which live outside of the table itself.
+    pub fn allocated_size(&self) -> usize {
+        std::mem::size_of_val(&*self.entries)
+    }
+
     fn capacity(&self) -> usize {
         self.entries.len()
     }
@@ -108,21 +141,19 @@ impl Table {
     // - occupied entry with same key
     // - first tombstone slot
     // - vacant slot
-    fn find(&self, key: &str) -> &Slot {
-        let mut index = hash(key.as_bytes()) % self.capacity() as u32;
+    fn find(&self, key: Gc<ObjString>) -> &Slot {
+        let mut index = key.hash() % self.capacity() as u32;
         let mut tombstone = None;
         loop {
             let entry = &self.entries[index as usize];
             match entry {
                 Slot::Occupied(OccupiedEntry { key: entry_key, .. })
-                    if entry_key.as_str() != key =>
-                {
-                    ()
-                }
+                    if !Gc::ptr_eq(*entry_key, key) => {}
+                Slot::Occupied(_) => return entry,
                 Slot::Tombstone => {
                     tombstone.get_or_insert(index);
                 }
-                Slot::Occupied(_) | Slot::Vacant => {
+                Slot::Vacant => {
                     if let Some(index) = tombstone {
                         return &self.entries[index as usize];
                     }
@@ -134,24 +165,20 @@ impl Table {
     }
 
     // same as `find`
-    fn find_mut(&mut self, key: &str) -> &mut Slot {
-        let mut index = hash(key.as_bytes()) % self.capacity() as u32;
+    fn find_mut(&mut self, key: Gc<ObjString>) -> &mut Slot {
+        let mut index = key.hash() % self.capacity() as u32;
         let mut tombstone = None;
         loop {
             let entry = &mut self.entries[index as usize];
             match entry {
                 Slot::Occupied(OccupiedEntry { key: entry_key, .. })
-                    if entry_key.as_str() != key =>
-                {
-                    ()
-                }
+                    if !Gc::ptr_eq(*entry_key, key) => {}
+                Slot::Occupied(_) => return &mut self.entries[index as usize],
                 Slot::Tombstone => {
                     tombstone.get_or_insert(index);
                 }
-                Slot::Occupied(_) | Slot::Vacant => {
-                    if let Some(index) = tombstone {
-                        return &mut self.entries[index as usize];
-                    }
+                Slot::Vacant => {
+                    let index = tombstone.unwrap_or(index);
                     return &mut self.entries[index as usize];
                 }
             }
@@ -160,8 +187,11 @@ impl Table {
     }
 }
 
-impl Extend<(String, Value)> for Table {
-    fn extend<T: IntoIterator<Item = (String, Value)>>(&mut self, iter: T) {
+impl Extend<(Gc<ObjString>, Value)> for Table {
+    fn extend<T: IntoIterator<Item = (Gc<ObjString>, Value)>>(
+        &mut self,
+        iter: T,
+    ) {
         for (key, value) in iter {
             self.set(key, value);
         }
@@ -192,9 +222,13 @@ enum Slot {
 }
 
 struct OccupiedEntry {
-    key: ThinString,
+    key: Gc<ObjString>,
     value: Value,
 }
 
-// Entry can just reuse Value's tag niches for its tag
-const _: () = assert!(size_of::<Slot>() == size_of::<OccupiedEntry>());
+// Entry can just reuse Value's tag niches for its tag,
+// nan-boxed values don't have any to spare
+#[cfg(not(feature = "nan-boxing"))]
+const _: () = assert!(
+    std::mem::size_of::<Slot>() == std::mem::size_of::<OccupiedEntry>()
+);
@@ -1,76 +1,150 @@
 // mod thin_string;
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing;
 
-use std::{mem::size_of, ops::Deref};
+use std::{fmt, mem::size_of};
+
+use crate::{
+    memory::Gc,
+    object::{
+        ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance,
+        ObjNative, ObjString,
+    },
+};
 
 // pub use self::thin_string::ThinString;
 
-// todo: nan-boxing
-#[derive(PartialEq, Clone)]
-pub enum Value {
+#[cfg(feature = "nan-boxing")]
+pub use self::nan_boxing::Value;
+
+/// Value in a form that can be matched on, see [`Value::unpack`].
+#[derive(Clone, Copy)]
+pub enum Unpacked {
     Nil,
     Bool(bool),
     Number(f64),
-    String(ThinString),
+    String(Gc<ObjString>),
+    Function(Gc<ObjFunction>),
+    Native(Gc<ObjNative>),
+    Closure(Gc<ObjClosure>),
+    Class(Gc<ObjClass>),
+    Instance(Gc<ObjInstance>),
+    BoundMethod(Gc<ObjBoundMethod>),
 }
 
-impl Value {
-    pub fn string(v: String) -> Self {
-        Value::String(ThinString::new(v))
+impl PartialEq for Unpacked {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            (Unpacked::Bool(a), Unpacked::Bool(b)) => a == b,
+            (Unpacked::Number(a), Unpacked::Number(b)) => a == b,
+            // strings are interned, so same contents means same object
+            (Unpacked::String(a), Unpacked::String(b)) => Gc::ptr_eq(*a, *b),
+            (Unpacked::Function(a), Unpacked::Function(b)) => {
+                Gc::ptr_eq(*a, *b)
+            }
+            (Unpacked::Native(a), Unpacked::Native(b)) => Gc::ptr_eq(*a, *b),
+            (Unpacked::Closure(a), Unpacked::Closure(b)) => Gc::ptr_eq(*a, *b),
+            (Unpacked::Class(a), Unpacked::Class(b)) => Gc::ptr_eq(*a, *b),
+            (Unpacked::Instance(a), Unpacked::Instance(b)) => {
+                Gc::ptr_eq(*a, *b)
+            }
+            (Unpacked::BoundMethod(a), Unpacked::BoundMethod(b)) => {
+                Gc::ptr_eq(*a, *b)
+            }
+            _ => false,
+        }
     }
 }
 
-// if/when implementing nan-boxing, update to just sizeof(u64/f64)
-const _: () = assert!(size_of::<Value>() == 2 * size_of::<u64>());
+// default representation, just the tagged enum
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy, PartialEq)]
+pub struct Value(Unpacked);
 
-pub const NIL: Value = Value::Nil;
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Self = Self(Unpacked::Nil);
 
-pub fn print_value(value: &Value) {
-    match value {
-        Value::Nil => print!("nil"),
-        Value::Bool(b) => print!("{b}"),
-        Value::Number(n) => print!("{n}"),
-        Value::String(s) => print!("{}", s.as_str()),
+    pub fn unpack(self) -> Unpacked {
+        self.0
     }
 }
 
-pub fn values_equal(a: Value, b: Value) -> bool {
-    match (a, b) {
-        (Value::Nil, Value::Nil) => true,
-        (Value::Bool(a), Value::Bool(b)) => a == b,
-        (Value::Number(a), Value::Number(b)) => a == b,
-        (Value::String(a), Value::String(b)) => a == b,
-        _ => false,
+#[cfg(not(feature = "nan-boxing"))]
+impl From<Unpacked> for Value {
+    fn from(value: Unpacked) -> Self {
+        Self(value)
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
-pub struct ThinString(Box<(String, u32)>);
-
-const _: () = assert!(size_of::<ThinString>() == size_of::<usize>());
+#[cfg(not(feature = "nan-boxing"))]
+const _: () = assert!(size_of::<Value>() == 2 * size_of::<u64>());
+#[cfg(feature = "nan-boxing")]
+const _: () = assert!(size_of::<Value>() == size_of::<u64>());
 
-impl ThinString {
-    pub fn new(s: String) -> Self {
-        let hash = hash(s.as_bytes());
-        Self(Box::new((s, hash)))
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Unpacked::Bool(b).into()
     }
+}
 
-    pub fn hash(&self) -> u32 {
-        self.0 .1
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Unpacked::Number(n).into()
     }
+}
 
-    pub fn into_string(self) -> String {
-        self.0 .0
-    }
+macro_rules! value_from_object {
+    ($($ty:ident => $variant:ident),* $(,)?) => {$(
+        impl From<Gc<$ty>> for Value {
+            fn from(object: Gc<$ty>) -> Self {
+                Unpacked::$variant(object).into()
+            }
+        }
+    )*};
 }
 
-impl Deref for ThinString {
-    type Target = String;
+value_from_object! {
+    ObjString => String,
+    ObjFunction => Function,
+    ObjNative => Native,
+    ObjClosure => Closure,
+    ObjClass => Class,
+    ObjInstance => Instance,
+    ObjBoundMethod => BoundMethod,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0 .0
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unpack() {
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Bool(b) => write!(f, "{b}"),
+            Unpacked::Number(n) => write!(f, "{n}"),
+            Unpacked::String(s) => write!(f, "{s}"),
+            Unpacked::Function(function) => write!(f, "{function}"),
+            Unpacked::Native(_) => write!(f, "<native fn>"),
+            Unpacked::Closure(c) => write!(f, "{}", c.function),
+            Unpacked::Class(c) => write!(f, "{}", c.name),
+            Unpacked::Instance(i) => write!(f, "{} instance", i.class.name),
+            Unpacked::BoundMethod(b) => write!(f, "{}", b.method.function),
+        }
     }
 }
 
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unpack() {
+            Unpacked::String(s) => write!(f, "{:?}", s.as_str()),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+pub fn values_equal(a: Value, b: Value) -> bool {
+    a == b
+}
+
 pub fn hash(data: &[u8]) -> u32 {
     let mut hash: u32 = 2166136261;
     for &byte in data {
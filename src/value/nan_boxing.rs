@@ -0,0 +1,100 @@
+use crate::memory::{raw_kind, Gc, ObjKind};
+
+use super::Unpacked;
+
+// everything that isn't a number is stored as a quiet nan, with the rest of
+// the bits used for the payload: sign bit set means an object pointer,
+// otherwise low bits tag singletons
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL_BITS: u64 = QNAN | TAG_NIL;
+const FALSE_BITS: u64 = QNAN | TAG_FALSE;
+const TRUE_BITS: u64 = QNAN | TAG_TRUE;
+
+/// Value packed into a single `f64`.
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+impl Value {
+    pub const NIL: Self = Self(NIL_BITS);
+
+    fn is_number(self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    fn is_object(self) -> bool {
+        self.0 & (QNAN | SIGN_BIT) == QNAN | SIGN_BIT
+    }
+
+    pub fn unpack(self) -> Unpacked {
+        if self.is_number() {
+            return Unpacked::Number(f64::from_bits(self.0));
+        }
+        if !self.is_object() {
+            return match self.0 {
+                NIL_BITS => Unpacked::Nil,
+                FALSE_BITS => Unpacked::Bool(false),
+                TRUE_BITS => Unpacked::Bool(true),
+                _ => unreachable!(),
+            };
+        }
+        let ptr = (self.0 & !(SIGN_BIT | QNAN)) as *const ();
+        // SAFETY: pointer was packed from a `Gc` in `From<Unpacked>`,
+        // and its header tells the type it was packed from
+        unsafe {
+            match raw_kind(ptr) {
+                ObjKind::String => Unpacked::String(Gc::from_raw(ptr)),
+                ObjKind::Function => Unpacked::Function(Gc::from_raw(ptr)),
+                ObjKind::Native => Unpacked::Native(Gc::from_raw(ptr)),
+                ObjKind::Closure => Unpacked::Closure(Gc::from_raw(ptr)),
+                ObjKind::Class => Unpacked::Class(Gc::from_raw(ptr)),
+                ObjKind::Instance => Unpacked::Instance(Gc::from_raw(ptr)),
+                ObjKind::BoundMethod => {
+                    Unpacked::BoundMethod(Gc::from_raw(ptr))
+                }
+                ObjKind::Upvalue => unreachable!(),
+            }
+        }
+    }
+}
+
+impl From<Unpacked> for Value {
+    fn from(value: Unpacked) -> Self {
+        let object = |ptr: *const ()| {
+            let bits = ptr as u64;
+            debug_assert_eq!(bits & (SIGN_BIT | QNAN), 0);
+            Self(bits | SIGN_BIT | QNAN)
+        };
+        match value {
+            Unpacked::Nil => Self::NIL,
+            Unpacked::Bool(false) => Self(FALSE_BITS),
+            Unpacked::Bool(true) => Self(TRUE_BITS),
+            // only canonical nan is guaranteed to not look like a tag
+            Unpacked::Number(n) if n.is_nan() => Self(f64::NAN.to_bits()),
+            Unpacked::Number(n) => Self(n.to_bits()),
+            Unpacked::String(s) => object(s.into_raw()),
+            Unpacked::Function(f) => object(f.into_raw()),
+            Unpacked::Native(n) => object(n.into_raw()),
+            Unpacked::Closure(c) => object(c.into_raw()),
+            Unpacked::Class(c) => object(c.into_raw()),
+            Unpacked::Instance(i) => object(i.into_raw()),
+            Unpacked::BoundMethod(b) => object(b.into_raw()),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        // nan isn't equal to itself, otherwise bits are enough, as
+        // different objects live at different addresses
+        if self.is_number() && other.is_number() {
+            return f64::from_bits(self.0) == f64::from_bits(other.0);
+        }
+        self.0 == other.0
+    }
+}
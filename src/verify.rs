@@ -0,0 +1,616 @@
+//! Static checks of bytecode that didn't come straight from the compiler.
+//!
+//! The vm trusts its code: operands index constants, locals and upvalues
+//! without bounds checks, and jumps go wherever they point. Compiled code
+//! upholds all of that, bytecode read from a file might not, so it has to
+//! pass [`verify`] before it runs.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    chunk::{BytecodeError, Opcode},
+    object::ObjFunction,
+    value::Unpacked,
+};
+
+/// Checks `function`, and every function in its constants, for:
+///
+/// - unknown opcodes and operands cut off by the end of code,
+/// - constant, local and upvalue operands out of bounds,
+/// - constants of wrong type, e.g. a global's name that isn't a string,
+/// - jumps landing outside of code or in the middle of an instruction,
+/// - stack depth that depends on the path taken to an instruction, or
+///   goes below the frame's locals, and code running off its end,
+/// - locals captured by a closure leaving the stack without getting
+///   closed first, which would leave the closure pointing past its top.
+///
+/// Types of values on the stack can't be known without running the code,
+/// so those are still checked at runtime.
+pub fn verify(function: &ObjFunction) -> Result<(), BytecodeError> {
+    let verifier = Verifier { function };
+    let instructions = verifier.decode()?;
+    verifier.check_jumps(&instructions)?;
+    verifier.check_stack(&instructions)?;
+    for constant in function.chunk.constants() {
+        if let Unpacked::Function(inner) = constant.unpack() {
+            verify(&inner)?;
+        }
+    }
+    Ok(())
+}
+
+struct Instruction {
+    // including the opcode
+    len: usize,
+    // values taken off the stack, then put back on
+    pops: usize,
+    pushes: usize,
+    // highest local slot it reads or writes
+    slot: Option<usize>,
+    // local slots a closure captures
+    captures: Vec<usize>,
+    jump: Option<usize>,
+    falls_through: bool,
+}
+
+impl Instruction {
+    fn new(len: usize, pops: usize, pushes: usize) -> Self {
+        Self {
+            len,
+            pops,
+            pushes,
+            slot: None,
+            captures: vec![],
+            jump: None,
+            falls_through: true,
+        }
+    }
+}
+
+struct Verifier<'f> {
+    function: &'f ObjFunction,
+}
+
+impl<'f> Verifier<'f> {
+    fn code(&self) -> &'f [u8] {
+        self.function.chunk.code()
+    }
+
+    fn error(
+        &self,
+        offset: usize,
+        message: impl Into<String>,
+    ) -> BytecodeError {
+        BytecodeError::Invalid {
+            function: self.function.name.clone(),
+            offset,
+            message: message.into(),
+        }
+    }
+
+    fn operands(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> Result<&'f [u8], BytecodeError> {
+        self.code()
+            .get(offset + 1..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or_else(|| {
+                self.error(offset, "operands cut off by end of code")
+            })
+    }
+
+    fn byte(&self, offset: usize) -> Result<usize, BytecodeError> {
+        Ok(self.operands(offset, 1)?[0].into())
+    }
+
+    fn short(&self, offset: usize) -> Result<usize, BytecodeError> {
+        let bytes = self.operands(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]).into())
+    }
+
+    fn long(&self, offset: usize) -> Result<usize, BytecodeError> {
+        let bytes = self.operands(offset, 3)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as usize)
+    }
+
+    // constant operand of `op` and the bytes it takes, three if it's
+    // a long form
+    fn constant_operand(
+        &self,
+        offset: usize,
+        op: Opcode,
+    ) -> Result<(usize, usize), BytecodeError> {
+        match op.is_long() {
+            true => Ok((self.long(offset)?, 3)),
+            false => Ok((self.byte(offset)?, 1)),
+        }
+    }
+
+    fn constant(
+        &self,
+        offset: usize,
+        id: usize,
+    ) -> Result<Unpacked, BytecodeError> {
+        let constants = self.function.chunk.constants();
+        match constants.get(id) {
+            Some(constant) => Ok(constant.unpack()),
+            None => Err(self.error(offset, format!("no constant {id}"))),
+        }
+    }
+
+    fn string(&self, offset: usize, id: usize) -> Result<(), BytecodeError> {
+        match self.constant(offset, id)? {
+            Unpacked::String(_) => Ok(()),
+            _ => Err(self.error(offset, format!("constant {id} isn't a name"))),
+        }
+    }
+
+    fn upvalue(
+        &self,
+        offset: usize,
+        index: usize,
+    ) -> Result<(), BytecodeError> {
+        if index >= self.function.upvalue_count {
+            return Err(self.error(offset, format!("no upvalue {index}")));
+        }
+        Ok(())
+    }
+
+    // instruction starting at each offset, `None` in between them
+    fn decode(&self) -> Result<Vec<Option<Instruction>>, BytecodeError> {
+        let code = self.code();
+        let mut instructions: Vec<_> = code.iter().map(|_| None).collect();
+        let mut offset = 0;
+        while offset < code.len() {
+            let instruction = self.decode_one(offset)?;
+            let len = instruction.len;
+            instructions[offset] = Some(instruction);
+            offset += len;
+        }
+        Ok(instructions)
+    }
+
+    fn decode_one(&self, offset: usize) -> Result<Instruction, BytecodeError> {
+        let byte = self.code()[offset];
+        let Some(op) = Opcode::from_u8(byte) else {
+            return Err(self.error(offset, format!("unknown opcode {byte}")));
+        };
+        let instruction = match op {
+            Opcode::Return => Instruction {
+                falls_through: false,
+                ..Instruction::new(1, 1, 0)
+            },
+            Opcode::Constant => {
+                self.constant(offset, self.byte(offset)?)?;
+                Instruction::new(2, 0, 1)
+            }
+            Opcode::ConstantLong => {
+                self.constant(offset, self.long(offset)?)?;
+                Instruction::new(4, 0, 1)
+            }
+            Opcode::Nil | Opcode::True | Opcode::False => {
+                Instruction::new(1, 0, 1)
+            }
+            Opcode::Pop | Opcode::Print | Opcode::CloseUpvalue => {
+                Instruction::new(1, 1, 0)
+            }
+            Opcode::Dup => Instruction::new(1, 1, 2),
+            Opcode::GetLocal | Opcode::SetLocal => {
+                let (pops, pushes) = match op {
+                    Opcode::GetLocal => (0, 1),
+                    _ => (1, 1),
+                };
+                Instruction {
+                    slot: Some(self.byte(offset)?),
+                    ..Instruction::new(2, pops, pushes)
+                }
+            }
+            Opcode::GetGlobal | Opcode::DefineGlobal | Opcode::SetGlobal => {
+                self.string(offset, self.byte(offset)?)?;
+                global_instruction(op, 2)
+            }
+            Opcode::GetGlobalLong
+            | Opcode::DefineGlobalLong
+            | Opcode::SetGlobalLong => {
+                self.string(offset, self.long(offset)?)?;
+                global_instruction(op, 4)
+            }
+            Opcode::GetUpvalue | Opcode::SetUpvalue => {
+                self.upvalue(offset, self.byte(offset)?)?;
+                match op {
+                    Opcode::GetUpvalue => Instruction::new(2, 0, 1),
+                    _ => Instruction::new(2, 1, 1),
+                }
+            }
+            Opcode::GetProperty
+            | Opcode::GetPropertyLong
+            | Opcode::SetProperty
+            | Opcode::SetPropertyLong
+            | Opcode::GetSuper
+            | Opcode::GetSuperLong => {
+                let (id, width) = self.constant_operand(offset, op)?;
+                self.string(offset, id)?;
+                match op {
+                    Opcode::GetProperty | Opcode::GetPropertyLong => {
+                        Instruction::new(1 + width, 1, 1)
+                    }
+                    _ => Instruction::new(1 + width, 2, 1),
+                }
+            }
+            Opcode::Equal
+            | Opcode::Greater
+            | Opcode::Less
+            | Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Divide
+            | Opcode::Inherit => Instruction::new(1, 2, 1),
+            Opcode::Not | Opcode::Negate => Instruction::new(1, 1, 1),
+            Opcode::Jump | Opcode::JumpIfFalse => {
+                let target = offset + 3 + self.short(offset)?;
+                let (pops, pushes) = match op {
+                    Opcode::Jump => (0, 0),
+                    _ => (1, 1),
+                };
+                Instruction {
+                    jump: Some(target),
+                    falls_through: matches!(op, Opcode::JumpIfFalse),
+                    ..Instruction::new(3, pops, pushes)
+                }
+            }
+            Opcode::Loop => {
+                let Some(target) =
+                    (offset + 3).checked_sub(self.short(offset)?)
+                else {
+                    return Err(self.error(offset, "loop jumps before code"));
+                };
+                Instruction {
+                    jump: Some(target),
+                    falls_through: false,
+                    ..Instruction::new(3, 0, 0)
+                }
+            }
+            Opcode::Call => {
+                let arg_count = self.byte(offset)?;
+                Instruction::new(2, arg_count + 1, 1)
+            }
+            Opcode::Invoke
+            | Opcode::InvokeLong
+            | Opcode::SuperInvoke
+            | Opcode::SuperInvokeLong => {
+                let (id, width) = self.constant_operand(offset, op)?;
+                self.string(offset, id)?;
+                let arg_count =
+                    usize::from(self.operands(offset, width + 1)?[width]);
+                // receiver, then the superclass for `SuperInvoke`
+                let pops = match op {
+                    Opcode::Invoke | Opcode::InvokeLong => arg_count + 1,
+                    _ => arg_count + 2,
+                };
+                Instruction::new(2 + width, pops, 1)
+            }
+            Opcode::Closure | Opcode::ClosureLong => {
+                self.decode_closure(offset, op)?
+            }
+            Opcode::Class | Opcode::ClassLong => {
+                let (id, width) = self.constant_operand(offset, op)?;
+                self.string(offset, id)?;
+                Instruction::new(1 + width, 0, 1)
+            }
+            Opcode::Method | Opcode::MethodLong => {
+                let (id, width) = self.constant_operand(offset, op)?;
+                self.string(offset, id)?;
+                Instruction::new(1 + width, 2, 1)
+            }
+        };
+        Ok(instruction)
+    }
+
+    // followed by a pair of `is_local` and index for each upvalue
+    fn decode_closure(
+        &self,
+        offset: usize,
+        op: Opcode,
+    ) -> Result<Instruction, BytecodeError> {
+        let (id, width) = self.constant_operand(offset, op)?;
+        let Unpacked::Function(function) = self.constant(offset, id)? else {
+            return Err(
+                self.error(offset, format!("constant {id} isn't a function"))
+            );
+        };
+        let operands =
+            self.operands(offset, width + 2 * function.upvalue_count)?;
+        let mut captures = vec![];
+        for pair in operands[width..].chunks(2) {
+            let index = usize::from(pair[1]);
+            match pair[0] {
+                1 => captures.push(index),
+                0 => self.upvalue(offset, index)?,
+                flag => {
+                    let message = format!("upvalue flag {flag} isn't 0 or 1");
+                    return Err(self.error(offset, message));
+                }
+            }
+        }
+        Ok(Instruction {
+            slot: captures.iter().copied().max(),
+            captures,
+            ..Instruction::new(1 + operands.len(), 0, 1)
+        })
+    }
+
+    fn check_jumps(
+        &self,
+        instructions: &[Option<Instruction>],
+    ) -> Result<(), BytecodeError> {
+        for (offset, instruction) in instructions.iter().enumerate() {
+            let Some(target) = instruction.as_ref().and_then(|i| i.jump) else {
+                continue;
+            };
+            if !matches!(instructions.get(target), Some(Some(_))) {
+                return Err(self.error(
+                    offset,
+                    format!("jump to {target:04}, which isn't an instruction"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // walks every path through the code, tracking how many values are on
+    // the stack, starting with the callee and its arguments, and which of
+    // them might be captured by closures
+    fn check_stack(
+        &self,
+        instructions: &[Option<Instruction>],
+    ) -> Result<(), BytecodeError> {
+        if instructions.is_empty() {
+            return Err(self.error(0, "no code"));
+        }
+        let mut states: Vec<Option<(usize, BTreeSet<usize>)>> =
+            vec![None; instructions.len()];
+        let start = usize::from(self.function.arity) + 1;
+        let mut pending = vec![(0, start, BTreeSet::new())];
+        while let Some((offset, depth, mut captured)) = pending.pop() {
+            match &mut states[offset] {
+                Some((known, _)) if *known != depth => {
+                    return Err(self.error(
+                        offset,
+                        format!(
+                            "stack holds {known} values on one path here, \
+                             {depth} on another"
+                        ),
+                    ))
+                }
+                // captured on any path counts, so only check again with
+                // slots no other path has captured yet
+                Some((_, known)) if captured.is_subset(known) => continue,
+                Some((_, known)) => {
+                    known.extend(&captured);
+                    captured.clone_from(known);
+                }
+                None => states[offset] = Some((depth, captured.clone())),
+            }
+            // only instruction starts ever get queued
+            let instruction = instructions[offset].as_ref().unwrap();
+            if instruction.pops > depth {
+                return Err(self.error(
+                    offset,
+                    format!(
+                        "pops {} values, but stack holds {depth}",
+                        instruction.pops
+                    ),
+                ));
+            }
+            if let Some(slot) = instruction.slot.filter(|&slot| slot >= depth) {
+                return Err(self.error(
+                    offset,
+                    format!("no local {slot}, stack holds {depth} values"),
+                ));
+            }
+            let depth = depth - instruction.pops;
+            match Opcode::from_u8(self.code()[offset]) {
+                Some(Opcode::CloseUpvalue) => {
+                    captured.retain(|&slot| slot < depth);
+                }
+                Some(Opcode::Return) => captured.clear(),
+                _ => {
+                    if let Some(slot) = captured.range(depth..).next() {
+                        return Err(self.error(
+                            offset,
+                            format!(
+                                "pops captured local {slot} without closing it"
+                            ),
+                        ));
+                    }
+                }
+            }
+            captured.extend(&instruction.captures);
+            let depth = depth + instruction.pushes;
+            if instruction.falls_through {
+                let next = offset + instruction.len;
+                if next == instructions.len() {
+                    return Err(self.error(offset, "runs off the end of code"));
+                }
+                pending.push((next, depth, captured.clone()));
+            }
+            if let Some(target) = instruction.jump {
+                pending.push((target, depth, captured));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn global_instruction(op: Opcode, len: usize) -> Instruction {
+    match op {
+        Opcode::GetGlobal | Opcode::GetGlobalLong => {
+            Instruction::new(len, 0, 1)
+        }
+        Opcode::DefineGlobal | Opcode::DefineGlobalLong => {
+            Instruction::new(len, 1, 0)
+        }
+        _ => Instruction::new(len, 1, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        chunk::Chunk,
+        compiler::{compile, Mode},
+        memory::Heap,
+        scanner::Span,
+        value::Value,
+        vm::Error,
+        Vm,
+    };
+
+    fn function(code: &[u8], constants: &[Value]) -> ObjFunction {
+        let mut chunk = Chunk::default();
+        for &byte in code {
+            chunk.write_byte(byte, 1, Span::default());
+        }
+        for &constant in constants {
+            chunk.add_constant(constant);
+        }
+        ObjFunction {
+            chunk,
+            ..ObjFunction::default()
+        }
+    }
+
+    fn error(code: &[u8], constants: &[Value]) -> String {
+        match verify(&function(code, constants)) {
+            Ok(()) => panic!("verified {code:?}"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    use Opcode::*;
+
+    #[test]
+    fn accepts_compiled_scripts() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/lox");
+        let mut heap = Heap::default();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let source = fs::read_to_string(&path).unwrap();
+            // some of them are there for their compile errors
+            if let Ok(function) = compile(&source, &mut heap, Mode::Script) {
+                let result = verify(&function);
+                assert!(result.is_ok(), "{}: {result:?}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_opcode() {
+        assert_eq!(
+            error(&[Nil.as_u8(), 255], &[]),
+            "Invalid bytecode at 0001 in script: unknown opcode 255."
+        );
+    }
+
+    #[test]
+    fn rejects_operands_out_of_bounds() {
+        let one = Value::from(1.0);
+        assert!(error(&[Constant.as_u8(), 1, Return.as_u8()], &[one])
+            .ends_with("no constant 1."));
+        assert!(error(&[GetLocal.as_u8(), 1, Return.as_u8()], &[])
+            .ends_with("no local 1, stack holds 1 values."));
+        assert!(error(&[GetUpvalue.as_u8(), 0, Return.as_u8()], &[])
+            .ends_with("no upvalue 0."));
+        assert!(error(&[Nil.as_u8(), Jump.as_u8(), 0], &[])
+            .ends_with("operands cut off by end of code."));
+    }
+
+    #[test]
+    fn rejects_global_name_that_isnt_a_string() {
+        let code = [Nil.as_u8(), DefineGlobal.as_u8(), 0, Nil.as_u8()];
+        assert!(
+            error(&[&code[..], &[Return.as_u8()]].concat(), &[Value::NIL])
+                .ends_with("constant 0 isn't a name.")
+        );
+    }
+
+    #[test]
+    fn rejects_jump_into_operand() {
+        // lands on the operand of `Constant`
+        let code = [Jump.as_u8(), 1, 0, Constant.as_u8(), 0, Return.as_u8()];
+        assert_eq!(
+            error(&code, &[Value::NIL]),
+            "Invalid bytecode at 0000 in script: \
+             jump to 0004, which isn't an instruction."
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_stack() {
+        // pushes an extra value each time around the loop
+        let code = [Nil.as_u8(), Loop.as_u8(), 4, 0];
+        assert!(error(&code, &[]).ends_with("2 on another."));
+        assert!(error(&[Pop.as_u8(), Pop.as_u8(), Return.as_u8()], &[])
+            .ends_with("pops 1 values, but stack holds 0."));
+        assert!(error(&[Add.as_u8(), Return.as_u8()], &[])
+            .ends_with("pops 2 values, but stack holds 1."));
+    }
+
+    #[test]
+    fn rejects_popping_captured_local() {
+        let mut heap = Heap::default();
+        let inner = ObjFunction {
+            upvalue_count: 1,
+            ..function(&[GetUpvalue.as_u8(), 0, Return.as_u8()], &[])
+        };
+        let inner = Value::from(heap.alloc(inner));
+        // keeps a closure over `x` in slot 1, then calls it once `x` and
+        // the slot below are gone, so `x` would be past the stack's top
+        let script = |drop_x: Opcode| {
+            let code = [
+                Nil.as_u8(),
+                Nil.as_u8(),
+                Nil.as_u8(),
+                Closure.as_u8(),
+                0,
+                1,
+                3,
+                SetLocal.as_u8(),
+                1,
+                Pop.as_u8(),
+                drop_x.as_u8(),
+                Pop.as_u8(),
+                GetLocal.as_u8(),
+                1,
+                Call.as_u8(),
+                0,
+                Return.as_u8(),
+            ];
+            function(&code, &[inner]).chunk.serialize()
+        };
+
+        let mut vm = Vm::default();
+        assert!(vm.interpret_bytecode(&script(CloseUpvalue)).is_ok());
+        match vm.interpret_bytecode(&script(Pop)) {
+            Err(Error::Bytecode(error)) => assert_eq!(
+                error.to_string(),
+                "Invalid bytecode at 0010 in script: \
+                 pops captured local 3 without closing it."
+            ),
+            result => panic!("ran {result:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_running_off_the_end() {
+        assert!(
+            error(&[Nil.as_u8()], &[]).ends_with("runs off the end of code.")
+        );
+        assert!(error(&[], &[]).ends_with("no code."));
+    }
+}
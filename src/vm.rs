@@ -1,62 +1,269 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    fmt,
+    io::{self, Write},
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use crate::{
-    chunk::{Chunk, Opcode},
-    common::DEBUG_TRACE_EXECUTION,
-    compiler::{compile, CompileError},
+    chunk::{BytecodeError, Chunk, Opcode},
+    common::{DEBUG_LOG_GC, DEBUG_STRESS_GC},
+    compiler::{compile, CompileError, Mode},
     debug::disassembly_instruction,
+    diagnostic::excerpt,
+    host::{self, NativeFn, Roots, VmId},
+    memory::{Gc, Heap, Object},
+    object::{
+        ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance,
+        ObjNative, ObjString, ObjUpvalue,
+    },
+    scanner::Span,
+    script::Script,
     table::Table,
-    value::{self, print_value, values_equal, Value},
+    value::{values_equal, Unpacked, Value},
+    verify::verify,
 };
 
-pub const STACK_MAX: usize = 256;
+pub const FRAMES_MAX: usize = 64;
+// slots each frame gets on average, as many as one function can address
+const FRAME_SLOTS: usize = 256;
+pub const STACK_MAX: usize = FRAMES_MAX * FRAME_SLOTS;
 
-// todo: string interning
-#[derive(Default)]
 pub struct Vm {
-    chunk: Chunk,
-    ip: usize,
+    frames: Vec<CallFrame>,
+    max_frames: usize,
     stack: Stack,
     globals: Table,
+    // sorted by stack slot
+    open_upvalues: Vec<Gc<ObjUpvalue>>,
+    // interned once, so looking up initializers doesn't have to
+    init_string: Gc<ObjString>,
+    heap: Heap,
+    // objects the host holds on to
+    roots: RefCell<Roots>,
+    id: VmId,
+    // where `print` writes to
+    output: Box<dyn Output>,
+    print_code: bool,
+    trace_execution: bool,
+    trace_output: Box<dyn Write>,
 }
 
-struct Stack {
-    storage: [Value; STACK_MAX],
-    top: usize,
+impl Default for Vm {
+    fn default() -> Self {
+        Self::with_stack_size(StackSize::default())
+    }
+}
+
+struct CallFrame {
+    closure: Gc<ObjClosure>,
+    ip: usize,
+    // index of the frame's first stack slot
+    slots: usize,
+}
+
+/// How many values the vm's stack can hold and how deep calls can nest,
+/// see [`Vm::with_stack_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackSize {
+    /// Slots allocated upfront.
+    pub initial: usize,
+    /// Going over this many slots is a "Stack overflow." error.
+    pub max: usize,
+    /// Same for calls nested deeper than this. [`FRAMES_MAX`] by default,
+    /// the constructors leave it unlimited, so only `max` bounds calls.
+    pub max_frames: usize,
+}
+
+impl StackSize {
+    /// Allocates everything upfront and never grows.
+    pub const fn fixed(size: usize) -> Self {
+        Self::growable(size, size)
+    }
+
+    /// Starts at `initial` slots, growing as needed, up to `max`.
+    pub const fn growable(initial: usize, max: usize) -> Self {
+        Self {
+            initial,
+            max,
+            max_frames: usize::MAX,
+        }
+    }
 }
 
-impl Default for Stack {
+impl Default for StackSize {
     fn default() -> Self {
         Self {
-            storage: [value::NIL; 256],
-            top: Default::default(),
+            max_frames: FRAMES_MAX,
+            ..Self::fixed(STACK_MAX)
         }
     }
 }
 
+// output that can be downcast back to what it was, see `Vm::take_output`
+trait Output: Write {
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<W: Write + 'static> Output for W {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+struct Stack {
+    // only ever holds live values, so its length is the top of the stack
+    storage: Vec<Value>,
+    max: usize,
+}
+
+struct StackOverflow;
+
 impl Stack {
-    fn reset(&mut self) {
-        self.top = 0;
+    fn new(size: StackSize) -> Self {
+        Self {
+            storage: Vec::with_capacity(size.initial),
+            max: size.max,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), StackOverflow> {
+        if self.storage.len() >= self.max {
+            return Err(StackOverflow);
+        }
+        self.storage.push(value);
+        Ok(())
     }
 
-    fn push(&mut self, value: Value) {
-        self.storage[self.top] = value;
-        self.top += 1;
+    // for keeping values rooted while the host calls into the vm,
+    // which has to work even if a native does it with a full stack
+    fn push_root(&mut self, value: Value) {
+        self.storage.push(value);
     }
 
     fn pop(&mut self) -> Value {
-        self.top -= 1;
-        std::mem::replace(&mut self.storage[self.top], Value::Nil)
+        self.storage.pop().unwrap()
+    }
+
+    fn truncate(&mut self, top: usize) {
+        self.storage.truncate(top);
     }
 
     #[track_caller]
     fn peek(&self, distance: usize) -> &Value {
-        &self.storage[self.top - distance - 1]
+        &self.storage[self.storage.len() - distance - 1]
+    }
+
+    #[track_caller]
+    fn peek_mut(&mut self, distance: usize) -> &mut Value {
+        let top = self.storage.len();
+        &mut self.storage[top - distance - 1]
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
     Compile(CompileError),
-    Runtime,
+    /// Compiled script couldn't be loaded.
+    Bytecode(BytecodeError),
+    Runtime(RuntimeError),
+}
+
+/// Error raised while running a script, either by the vm itself
+/// or returned from a native function.
+///
+/// Its `Display` impl renders message and stack trace, in same format as
+/// clox does, but printing it is up to the caller.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    /// Active frames at the point of error, innermost first.
+    /// Filled in by the vm, natives should leave it empty.
+    pub trace: Vec<TraceFrame>,
+    /// Part of the source that failed, e.g. the operand of wrong type.
+    /// Filled in by the vm, same as `trace`.
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// Operation applied to value of wrong type, e.g. adding nil.
+    TypeError,
+    UndefinedVariable,
+    UndefinedProperty,
+    /// Call with a different number of arguments than the callee expects.
+    WrongArity,
+    StackOverflow,
+    /// Byte that isn't a valid opcode.
+    UnknownInstruction,
+    /// Raised by a native function.
+    Native,
+    /// Writing to the output set with [`Vm::set_output`] failed.
+    Output,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    /// `None` for top-level script.
+    pub function: Option<String>,
+    pub line: u32,
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            trace: vec![],
+            span: None,
+        }
+    }
+
+    /// Renders the error with excerpt of `source` it points at,
+    /// followed by the stack trace.
+    ///
+    /// `source` has to be the one the failing function was compiled from.
+    pub fn render(&self, source: &str) -> String {
+        let mut rendered = format!("{}\n", self.message);
+        if let Some(span) = self.span {
+            rendered += &excerpt(source, span);
+        }
+        for frame in &self.trace {
+            rendered += &frame.to_string();
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => writeln!(f, "[line {}] in {name}()", self.line),
+            None => writeln!(f, "[line {}] in script", self.line),
+        }
+    }
 }
 
 impl From<CompileError> for Error {
@@ -65,28 +272,440 @@ impl From<CompileError> for Error {
     }
 }
 
+impl From<BytecodeError> for Error {
+    fn from(value: BytecodeError) -> Self {
+        Self::Bytecode(value)
+    }
+}
+
 impl Vm {
+    /// Vm whose stack holds at most `stack_size.max` values and
+    /// `stack_size.max_frames` nested calls, going over either, e.g. with
+    /// deep recursion, is a "Stack overflow." runtime error.
+    pub fn with_stack_size(stack_size: StackSize) -> Self {
+        let mut heap = Heap::default();
+        let init_string = heap.intern(String::from("init"));
+        let mut vm = Self {
+            frames: vec![],
+            max_frames: stack_size.max_frames,
+            stack: Stack::new(stack_size),
+            globals: Table::default(),
+            open_upvalues: vec![],
+            init_string,
+            heap,
+            roots: RefCell::default(),
+            id: VmId::new(),
+            output: Box::new(io::stdout()),
+            print_code: false,
+            trace_execution: false,
+            trace_output: Box::new(io::stderr()),
+        };
+        vm.define_native("clock", 0, clock_native);
+        vm
+    }
+
+    /// How much the heap may grow, as a multiple of the memory still in use
+    /// after a collection, before the next collection runs. Defaults to 2.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is less than 1.
+    pub fn set_heap_grow_factor(&mut self, factor: f64) {
+        self.heap.set_grow_factor(factor);
+    }
+
+    /// Where `print` statements write to, stdout by default.
+    /// Errors writing to it are raised as runtime errors in the script.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Takes back the output given to [`Vm::set_output`], e.g. a `Vec<u8>`
+    /// to see what scripts printed, and goes back to stdout.
+    ///
+    /// `None` if the output isn't a `W`, in which case it's left in place.
+    pub fn take_output<W: Write + 'static>(&mut self) -> Option<W> {
+        // on the `dyn Output` itself, not on its box, which is a `Write` too
+        if !(*self.output).as_any().is::<W>() {
+            return None;
+        }
+        let output = mem::replace(&mut self.output, Box::new(io::stdout()));
+        output.into_any().downcast().ok().map(|output| *output)
+    }
+
+    /// Disassembles every function before it runs.
+    ///
+    /// Does nothing unless the `trace` feature is enabled.
+    pub fn set_print_code(&mut self, enabled: bool) {
+        self.print_code = enabled;
+    }
+
+    /// Prints the stack and each instruction before executing it.
+    ///
+    /// Does nothing unless the `trace` feature is enabled.
+    pub fn set_trace_execution(&mut self, enabled: bool) {
+        self.trace_execution = enabled;
+    }
+
+    /// Where disassembly and execution trace are written, stderr by default.
+    /// Errors writing to it are ignored.
+    pub fn set_trace_output(&mut self, output: impl Write + 'static) {
+        self.trace_output = Box::new(output);
+    }
+
+    /// Compiles `source` and writes out its bytecode to the trace output,
+    /// without running it.
+    pub fn disassemble(&mut self, source: &str) -> Result<(), CompileError> {
+        let function = compile(source, &mut self.heap, Mode::Script)?;
+        let _ = self.disassemble_function(function, Some(source));
+        Ok(())
+    }
+
+    /// Same as [`Vm::disassemble`], but for a single expression.
+    pub fn disassemble_expression(
+        &mut self,
+        source: &str,
+    ) -> Result<(), CompileError> {
+        let function = compile(source, &mut self.heap, Mode::Expression)?;
+        let _ = self.disassemble_function(function, Some(source));
+        Ok(())
+    }
+
     pub fn interpret(&mut self, source: &str) -> Result<(), Error> {
-        let mut chunk = Chunk::default();
-        compile(source, &mut chunk)?;
-        self.chunk = chunk;
-        self.ip = 0;
-        self.run()
+        let function = compile(source, &mut self.heap, Mode::Script)?;
+        if cfg!(feature = "trace") && self.print_code {
+            let _ = self.disassemble_function(function, Some(source));
+        }
+        self.run_function(function)?;
+        Ok(())
     }
 
-    fn reset_stack(&mut self) {
-        self.stack.reset()
+    /// Runs `script` compiled by [`compile`](crate::compile), which
+    /// works like passing its source to [`Vm::interpret`].
+    pub fn run(&mut self, script: &Script) -> Result<(), Error> {
+        let function = script.copy_to(&mut self.heap);
+        if cfg!(feature = "trace") && self.print_code {
+            let _ = self.disassemble_function(function, None);
+        }
+        self.run_function(function)?;
+        Ok(())
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{message}");
-        let line = self.chunk.get_line(self.ip - 1);
-        eprintln!("[line {line}] in script");
-        self.reset_stack();
+    /// Like [`Vm::interpret`], but if `source` is a single expression
+    /// statement, returns its value instead of discarding it, the way
+    /// an interactive prompt would. Anything else returns nil.
+    pub fn interpret_repl(
+        &mut self,
+        source: &str,
+    ) -> Result<host::Value, Error> {
+        let function = compile(source, &mut self.heap, Mode::Repl)?;
+        if cfg!(feature = "trace") && self.print_code {
+            let _ = self.disassemble_function(function, Some(source));
+        }
+        let value = self.run_function(function)?;
+        Ok(self.export_value(value))
+    }
+
+    /// Evaluates single expression, like `a + b.c`, and returns its value.
+    pub fn eval_expression(
+        &mut self,
+        source: &str,
+    ) -> Result<host::Value, Error> {
+        let function = compile(source, &mut self.heap, Mode::Expression)?;
+        if cfg!(feature = "trace") && self.print_code {
+            let _ = self.disassemble_function(function, Some(source));
+        }
+        let value = self.run_function(function)?;
+        Ok(self.export_value(value))
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    /// Compiles `source` into bytecode that can be run later with
+    /// [`Vm::interpret_bytecode`]. The bytes start with [`MAGIC`](crate::MAGIC)
+    /// and [`BYTECODE_VERSION`](crate::BYTECODE_VERSION).
+    pub fn compile_bytecode(
+        &mut self,
+        source: &str,
+    ) -> Result<Vec<u8>, CompileError> {
+        let function = compile(source, &mut self.heap, Mode::Script)?;
+        Ok(function.chunk.serialize())
+    }
+
+    /// Same as [`Vm::interpret`], but for a script compiled beforehand.
+    /// Runtime errors have no spans, as there's no source to point into.
+    ///
+    /// `bytes` can come from anywhere, they're checked to be well-formed
+    /// and safe to run first.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let function = self.load_bytecode(bytes)?;
+        if cfg!(feature = "trace") && self.print_code {
+            let _ = self.disassemble_function(function, None);
+        }
+        self.run_function(function)?;
+        Ok(())
+    }
+
+    /// Same as [`Vm::disassemble`], but for a script compiled beforehand.
+    pub fn disassemble_bytecode(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<(), BytecodeError> {
+        let function = self.load_bytecode(bytes)?;
+        let _ = self.disassemble_function(function, None);
+        Ok(())
+    }
+
+    /// Every global variable, in no particular order.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, host::Value)> {
+        self.globals
+            .iter()
+            .map(|(name, &value)| (name.as_str(), self.export_value(value)))
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<host::Value> {
+        self.get_global_value(name)
+            .map(|value| self.export_value(value))
+    }
+
+    /// Defines or overwrites global `name`.
+    ///
+    /// # Panics
+    ///
+    /// If `value` is an [`Object`](host::Object) from another vm.
+    pub fn set_global(&mut self, name: &str, value: host::Value) {
+        let value = self.import_value(&value);
+        // interning might collect, so keep `value` rooted meanwhile
+        self.stack.push_root(value);
+        let name = self.intern(name.to_string());
+        self.globals.set(name, value);
+        self.pop();
+    }
+
+    /// Calls global function `name` and returns its result.
+    ///
+    /// # Panics
+    ///
+    /// If any of `args` is an [`Object`](host::Object) from another vm.
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: &[host::Value],
+    ) -> Result<host::Value, Error> {
+        let Some(callee) = self.get_global_value(name) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::UndefinedVariable,
+                format!("Undefined variable '{name}'."),
+            ));
+        };
+        let Ok(arg_count) = u8::try_from(args.len()) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::WrongArity,
+                "Can't have more than 255 arguments.",
+            ));
+        };
+        let value = self.unwinding(|vm| {
+            let base = vm.frames.len();
+            vm.push(callee)?;
+            for arg in args {
+                let arg = vm.import_value(arg);
+                vm.push(arg)?;
+            }
+            vm.call_value(callee, arg_count)?;
+            if vm.frames.len() == base {
+                // natives and classes without initializers finish right away
+                return Ok(vm.pop());
+            }
+            vm.execute(base)
+        })?;
+        Ok(self.export_value(value))
+    }
+
+    fn get_global_value(&self, name: &str) -> Option<Value> {
+        // if the name isn't interned, then no global could've been defined
+        let name = self.heap.find_interned(name)?;
+        self.globals.get(name).copied()
+    }
+
+    // strings are copied, other objects are rooted for as long as the host
+    // holds on to them
+    fn export_value(&self, value: Value) -> host::Value {
+        match value.unpack() {
+            Unpacked::Nil => host::Value::Nil,
+            Unpacked::Bool(b) => host::Value::Bool(b),
+            Unpacked::Number(n) => host::Value::Number(n),
+            Unpacked::String(s) => host::Value::String(s.as_str().to_string()),
+            _ => host::Value::Object(
+                self.roots.borrow_mut().root(self.id, value),
+            ),
+        }
+    }
+
+    // strings get interned, so this might collect
+    fn import_value(&mut self, value: &host::Value) -> Value {
+        match value {
+            host::Value::Nil => Value::NIL,
+            host::Value::Bool(b) => Value::from(*b),
+            host::Value::Number(n) => Value::from(*n),
+            host::Value::String(s) => Value::from(self.intern(s.clone())),
+            host::Value::Object(object) => {
+                assert!(object.vm() == self.id, "object is from another vm");
+                object.value()
+            }
+        }
+    }
+
+    fn load_bytecode(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<Gc<ObjFunction>, BytecodeError> {
+        let chunk = Chunk::deserialize(bytes, &mut self.heap)?;
+        let function = ObjFunction {
+            chunk,
+            ..ObjFunction::default()
+        };
+        verify(&function)?;
+        Ok(self.heap.alloc(function))
+    }
+
+    fn run_function(
+        &mut self,
+        function: Gc<ObjFunction>,
+    ) -> Result<Value, Error> {
+        self.unwinding(|vm| {
+            let base = vm.frames.len();
+            // keep the function rooted while allocating its closure
+            vm.push(Value::from(function))?;
+            let closure = vm.alloc(ObjClosure {
+                function,
+                upvalues: vec![],
+            });
+            vm.pop();
+            vm.push(Value::from(closure))?;
+            vm.call(closure, 0)?;
+            vm.execute(base)
+        })
+    }
+
+    // runs `f` and if it fails, unwinds frames and values it left behind,
+    // so a host calling back into the vm from a native can carry on
+    fn unwinding<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let frames = self.frames.len();
+        let top = self.stack.len();
+        let result = f(self);
+        if result.is_err() {
+            self.close_upvalues(top);
+            self.frames.truncate(frames);
+            self.stack.truncate(top);
+        }
+        result
+    }
+
+    /// Makes `function` callable from scripts as global `name`.
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        // both have to stay rooted until they are stored in globals
+        let name = self.intern(name.to_string());
+        self.stack.push_root(Value::from(name));
+        let native = self.alloc(ObjNative { arity, function });
+        self.stack.push_root(Value::from(native));
+        self.globals.set(name, *self.peek(0));
+        self.pop();
+        self.pop();
+    }
+
+    // anything referenced by `object` has to be reachable from the roots,
+    // as it might trigger a collection
+    fn alloc<T: Object>(&mut self, object: T) -> Gc<T> {
+        if DEBUG_STRESS_GC || self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(object)
+    }
+
+    // same as `alloc`
+    fn intern(&mut self, string: String) -> Gc<ObjString> {
+        if DEBUG_STRESS_GC || self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(string)
+    }
+
+    fn collect_garbage(&mut self) {
+        if DEBUG_LOG_GC {
+            println!("-- gc begin");
+        }
+        for &value in &self.stack.storage {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in self.globals.iter() {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        for value in self.roots.borrow().values() {
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_object(self.init_string);
+        self.heap.collect();
+    }
+
+    fn runtime_error(
+        &mut self,
+        kind: RuntimeErrorKind,
+        message: impl Into<String>,
+    ) -> Error {
+        self.raise(RuntimeError::new(kind, message))
+    }
+
+    // type error of the current single-byte instruction's operand,
+    // `operand` as in `Chunk::operand_span`
+    fn operand_error(&mut self, operand: usize, message: &str) -> Error {
+        let frame = self.frame();
+        let chunk = &frame.closure.function.chunk;
+        let mut error = RuntimeError::new(RuntimeErrorKind::TypeError, message);
+        error.span = chunk.operand_span(frame.ip - 1, operand);
+        self.raise(error)
+    }
+
+    // attaches stack trace to the error, the stack is unwound by whoever
+    // started running the failed code, see `Vm::unwinding`
+    fn raise(&mut self, mut error: RuntimeError) -> Error {
+        if error.span.is_none() {
+            error.span = self.frames.last().and_then(|frame| {
+                frame.closure.function.chunk.get_span(frame.ip - 1)
+            });
+        }
+        // error coming from a nested `call_function` already has its trace
+        if error.trace.is_empty() {
+            error.trace = self
+                .frames
+                .iter()
+                .rev()
+                .map(|frame| {
+                    let function = &frame.closure.function;
+                    TraceFrame {
+                        function: function.name.clone(),
+                        line: function.chunk.get_line(frame.ip - 1),
+                    }
+                })
+                .collect();
+        }
+        Error::Runtime(error)
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), Error> {
+        self.stack.push(value).map_err(|StackOverflow| {
+            self.runtime_error(
+                RuntimeErrorKind::StackOverflow,
+                "Stack overflow.",
+            )
+        })
     }
 
     fn pop(&mut self) -> Value {
@@ -98,13 +717,28 @@ impl Vm {
         self.stack.peek(distance)
     }
 
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
     fn read_byte(&mut self) -> u8 {
-        self.chunk.code()[read_and_inc(&mut self.ip)]
+        let frame = self.frame_mut();
+        frame.closure.function.chunk.code()[read_and_inc(&mut frame.ip)]
     }
 
-    fn read_constant(&mut self) -> &Value {
+    fn read_constant(&mut self) -> Value {
         let id = self.read_byte();
-        self.chunk.get_constant(id)
+        *self.frame().closure.function.chunk.get_constant(id.into())
+    }
+
+    fn read_constant_long(&mut self) -> Value {
+        let bytes = [self.read_byte(), self.read_byte(), self.read_byte(), 0];
+        let id = u32::from_le_bytes(bytes);
+        *self.frame().closure.function.chunk.get_constant(id)
     }
 
     fn read_short(&mut self) -> u16 {
@@ -113,25 +747,246 @@ impl Vm {
         u16::from_le_bytes([l, h])
     }
 
-    fn read_string(&mut self) -> String {
-        match self.read_constant() {
-            Value::String(s) => s.to_string(),
+    // constant operand of `op`, 24 bits wide if it's a long form
+    fn read_constant_of(&mut self, op: Opcode) -> Value {
+        match op.is_long() {
+            true => self.read_constant_long(),
+            false => self.read_constant(),
+        }
+    }
+
+    fn read_string(&mut self, op: Opcode) -> Gc<ObjString> {
+        match self.read_constant_of(op).unpack() {
+            Unpacked::String(s) => s,
             _ => unreachable!(),
         }
     }
 
+    fn call(
+        &mut self,
+        closure: Gc<ObjClosure>,
+        arg_count: u8,
+    ) -> Result<(), Error> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::WrongArity,
+                format!("Expected {arity} arguments but got {arg_count}."),
+            ));
+        }
+        if self.frames.len() >= self.max_frames {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::StackOverflow,
+                "Stack overflow.",
+            ));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - usize::from(arg_count) - 1,
+        });
+        Ok(())
+    }
+
+    fn call_value(
+        &mut self,
+        callee: Value,
+        arg_count: u8,
+    ) -> Result<(), Error> {
+        match callee.unpack() {
+            Unpacked::BoundMethod(bound) => {
+                *self.stack.peek_mut(arg_count.into()) = bound.receiver;
+                self.call(bound.method, arg_count)
+            }
+            Unpacked::Class(class) => {
+                // class is still on the stack in place of the instance
+                let instance = self.alloc(ObjInstance {
+                    class,
+                    fields: RefCell::default(),
+                });
+                *self.stack.peek_mut(arg_count.into()) = Value::from(instance);
+                let initializer =
+                    class.methods.borrow().get(self.init_string).copied();
+                match initializer.map(Value::unpack) {
+                    Some(Unpacked::Closure(initializer)) => {
+                        self.call(initializer, arg_count)
+                    }
+                    _ if arg_count != 0 => Err(self.runtime_error(
+                        RuntimeErrorKind::WrongArity,
+                        format!("Expected 0 arguments but got {arg_count}."),
+                    )),
+                    _ => Ok(()),
+                }
+            }
+            Unpacked::Closure(closure) => self.call(closure, arg_count),
+            Unpacked::Native(native) => {
+                let arity = native.arity;
+                if arg_count != arity {
+                    return Err(self.runtime_error(
+                        RuntimeErrorKind::WrongArity,
+                        format!(
+                            "Expected {arity} arguments but got {arg_count}."
+                        ),
+                    ));
+                }
+                // arguments stay on the stack, so they are rooted during call
+                let args_start = self.stack.len() - usize::from(arg_count);
+                let args: Vec<_> = self.stack.storage[args_start..]
+                    .iter()
+                    .map(|&arg| self.export_value(arg))
+                    .collect();
+                match (native.function)(self, &args) {
+                    Ok(result) => {
+                        let result = self.import_value(&result);
+                        self.stack.truncate(args_start - 1);
+                        self.push(result)?;
+                        Ok(())
+                    }
+                    Err(error) => Err(self.raise(error)),
+                }
+            }
+            _ => Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Can only call functions and classes.",
+            )),
+        }
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<ObjClass>,
+        name: Gc<ObjString>,
+        arg_count: u8,
+    ) -> Result<(), Error> {
+        let method = class.methods.borrow().get(name).copied();
+        let Some(Unpacked::Closure(method)) = method.map(Value::unpack) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::UndefinedProperty,
+                format!("Undefined property '{name}'."),
+            ));
+        };
+        self.call(method, arg_count)
+    }
+
+    fn invoke(
+        &mut self,
+        name: Gc<ObjString>,
+        arg_count: u8,
+    ) -> Result<(), Error> {
+        let Unpacked::Instance(instance) = self.peek(arg_count.into()).unpack()
+        else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Only instances have methods.",
+            ));
+        };
+        let field = instance.fields.borrow().get(name).copied();
+        if let Some(value) = field {
+            // fields shadow methods, so it's just a regular call
+            *self.stack.peek_mut(arg_count.into()) = value;
+            return self.call_value(value, arg_count);
+        }
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    // replaces instance on top of the stack with `name` method bound to it
+    fn bind_method(
+        &mut self,
+        class: Gc<ObjClass>,
+        name: Gc<ObjString>,
+    ) -> Result<(), Error> {
+        let method = class.methods.borrow().get(name).copied();
+        let Some(Unpacked::Closure(method)) = method.map(Value::unpack) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::UndefinedProperty,
+                format!("Undefined property '{name}'."),
+            ));
+        };
+        let bound = self.alloc(ObjBoundMethod {
+            receiver: *self.peek(0),
+            method,
+        });
+        self.pop();
+        self.push(Value::from(bound))?;
+        Ok(())
+    }
+
+    fn define_method(&mut self, name: Gc<ObjString>) -> Result<(), Error> {
+        let class = self.peek_class(1)?;
+        let method = self.pop();
+        self.grow_table(&class.methods, |methods| methods.set(name, method));
+        Ok(())
+    }
+
+    // compiled code always has a class there, but verified bytecode
+    // only guarantees that there's some value
+    fn peek_class(&mut self, distance: usize) -> Result<Gc<ObjClass>, Error> {
+        match self.peek(distance).unpack() {
+            Unpacked::Class(class) => Ok(class),
+            _ => Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Expected a class.",
+            )),
+        }
+    }
+
+    fn capture_upvalue(&mut self, location: usize) -> Gc<ObjUpvalue> {
+        let index = self
+            .open_upvalues
+            .partition_point(|upvalue| upvalue.location < location);
+        if let Some(&upvalue) = self.open_upvalues.get(index) {
+            if upvalue.location == location {
+                return upvalue;
+            }
+        }
+        let upvalue = self.alloc(ObjUpvalue {
+            location,
+            closed: Cell::new(None),
+        });
+        self.open_upvalues.insert(index, upvalue);
+        upvalue
+    }
+
+    // changes a table of some object, counting what it grows by towards
+    // the next collection, see `Object::extra_size`
+    fn grow_table<R>(
+        &mut self,
+        table: &RefCell<Table>,
+        f: impl FnOnce(&mut Table) -> R,
+    ) -> R {
+        let mut table = table.borrow_mut();
+        let before = table.allocated_size();
+        let result = f(&mut table);
+        self.heap
+            .grew(table.allocated_size().saturating_sub(before));
+        result
+    }
+
+    // closes all upvalues pointing at `last` slot or above it
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            if upvalue.location < last {
+                break;
+            }
+            upvalue
+                .closed
+                .set(Some(self.stack.storage[upvalue.location]));
+            self.open_upvalues.pop();
+        }
+    }
+
     fn binary_op<F: FnOnce(f64, f64) -> Value>(
         &mut self,
         f: F,
     ) -> Result<(), Error> {
         let b = self.pop();
         let a = self.pop();
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => self.push(f(a, b)),
-            _ => {
-                self.runtime_error("Operands must be numbers.");
-                return Err(Error::Runtime);
+        match (a.unpack(), b.unpack()) {
+            (Unpacked::Number(a), Unpacked::Number(b)) => self.push(f(a, b))?,
+            (Unpacked::Number(_), _) => {
+                return Err(self.operand_error(0, "Operands must be numbers."))
             }
+            _ => return Err(self.operand_error(1, "Operands must be numbers.")),
         }
         Ok(())
     }
@@ -149,147 +1004,329 @@ impl Vm {
         // }
     }
 
-    fn run(&mut self) -> Result<(), Error> {
+    // writes out `function` and every function nested in it, innermost first
+    fn disassemble_function(
+        &mut self,
+        function: Gc<ObjFunction>,
+        source: Option<&str>,
+    ) -> io::Result<()> {
+        for constant in function.chunk.constants() {
+            if let Unpacked::Function(inner) = constant.unpack() {
+                self.disassemble_function(inner, source)?;
+            }
+        }
+        let name = function.name.as_deref().unwrap_or("<script>");
+        let out = &mut self.trace_output;
+        function.chunk.disassembly(name, source, out)
+    }
+
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        let out = &mut self.trace_output;
+        write!(out, "          ")?;
+        for value in &self.stack.storage {
+            write!(out, "[ {value} ]")?;
+        }
+        writeln!(out)?;
+        let frame = self.frames.last().unwrap();
+        let chunk = &frame.closure.function.chunk;
+        disassembly_instruction(chunk, frame.ip, out)?;
+        Ok(())
+    }
+
+    // runs until the frame above `base` returns, then returns its result
+    fn execute(&mut self, base: usize) -> Result<Value, Error> {
         loop {
-            if DEBUG_TRACE_EXECUTION {
-                print!("          ");
-                for value in &self.stack.storage[..self.stack.top] {
-                    print!("[ ");
-                    print_value(value);
-                    print!(" ]");
-                }
-                println!();
-                disassembly_instruction(&self.chunk, self.ip);
+            if cfg!(feature = "trace") && self.trace_execution {
+                let _ = self.trace_instruction();
             }
             let instruction = self.read_byte();
             match Opcode::from_u8(instruction) {
                 Some(Opcode::Constant) => {
-                    let constant = self.read_constant().clone();
-                    self.push(constant);
+                    let constant = self.read_constant();
+                    self.push(constant)?;
+                }
+                Some(Opcode::ConstantLong) => {
+                    let constant = self.read_constant_long();
+                    self.push(constant)?;
                 }
-                Some(Opcode::Nil) => self.push(Value::Nil),
-                Some(Opcode::True) => self.push(Value::Bool(true)),
-                Some(Opcode::False) => self.push(Value::Bool(false)),
+                Some(Opcode::Nil) => self.push(Value::NIL)?,
+                Some(Opcode::True) => self.push(Value::from(true))?,
+                Some(Opcode::False) => self.push(Value::from(false))?,
                 Some(Opcode::Dup) => {
-                    self.push(self.peek(0).clone());
+                    self.push(*self.peek(0))?;
                 }
                 Some(Opcode::Pop) => {
                     self.pop();
                 }
                 Some(Opcode::GetLocal) => {
-                    let slot = self.read_byte();
-                    self.push(self.stack.storage[slot as usize].clone());
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.push(self.stack.storage[slot])?;
                 }
                 Some(Opcode::SetLocal) => {
-                    let slot = self.read_byte();
-                    self.stack.storage[slot as usize] =
-                        self.stack.peek(0).clone();
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack.storage[slot] = *self.stack.peek(0);
                 }
-                Some(Opcode::GetGlobal) => {
-                    let name = self.read_string();
-                    match self.globals.get(&name) {
-                        Some(value) => self.push(value.clone()),
+                Some(op @ (Opcode::GetGlobal | Opcode::GetGlobalLong)) => {
+                    let name = self.read_string(op);
+                    match self.globals.get(name) {
+                        Some(&value) => self.push(value)?,
                         None => {
-                            self.runtime_error(&format!(
-                                "Undefined variable '{name}'"
+                            self.print_similar_names(name.as_str());
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::UndefinedVariable,
+                                format!("Undefined variable '{name}'."),
                             ));
-                            self.print_similar_names(&name);
-                            return Err(Error::Runtime);
                         }
                     }
                 }
-                Some(Opcode::DefineGlobal) => {
-                    let name = self.read_string();
-                    self.globals.set(name, self.peek(0).clone());
+                Some(
+                    op @ (Opcode::DefineGlobal | Opcode::DefineGlobalLong),
+                ) => {
+                    let name = self.read_string(op);
+                    self.globals.set(name, *self.peek(0));
                     self.pop();
                 }
-                Some(Opcode::SetGlobal) => {
-                    let name = self.read_string();
-                    if let Some(value) = self.globals.get_mut(&name) {
-                        *value = self.stack.peek(0).clone();
+                Some(op @ (Opcode::SetGlobal | Opcode::SetGlobalLong)) => {
+                    let name = self.read_string(op);
+                    if let Some(value) = self.globals.get_mut(name) {
+                        *value = *self.stack.peek(0);
                     } else {
-                        self.runtime_error(&format!(
-                            "Undefined variable '{name}'"
+                        self.print_similar_names(name.as_str());
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::UndefinedVariable,
+                            format!("Undefined variable '{name}'."),
                         ));
-                        self.print_similar_names(&name);
-                        return Err(Error::Runtime);
                     }
                 }
+                Some(Opcode::GetUpvalue) => {
+                    let slot = self.read_byte();
+                    let upvalue = self.frame().closure.upvalues[slot as usize];
+                    let value = match upvalue.closed.get() {
+                        Some(value) => value,
+                        None => self.stack.storage[upvalue.location],
+                    };
+                    self.push(value)?;
+                }
+                Some(Opcode::SetUpvalue) => {
+                    let slot = self.read_byte();
+                    let upvalue = self.frame().closure.upvalues[slot as usize];
+                    let value = *self.peek(0);
+                    match upvalue.closed.get() {
+                        Some(_) => upvalue.closed.set(Some(value)),
+                        None => self.stack.storage[upvalue.location] = value,
+                    }
+                }
+                Some(op @ (Opcode::GetProperty | Opcode::GetPropertyLong)) => {
+                    let Unpacked::Instance(instance) = self.peek(0).unpack()
+                    else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Only instances have properties.",
+                        ));
+                    };
+                    let name = self.read_string(op);
+                    let field = instance.fields.borrow().get(name).copied();
+                    if let Some(value) = field {
+                        self.pop();
+                        self.push(value)?;
+                    } else {
+                        self.bind_method(instance.class, name)?;
+                    }
+                }
+                Some(op @ (Opcode::SetProperty | Opcode::SetPropertyLong)) => {
+                    let Unpacked::Instance(instance) = self.peek(1).unpack()
+                    else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Only instances have fields.",
+                        ));
+                    };
+                    let name = self.read_string(op);
+                    let value = self.pop();
+                    self.grow_table(&instance.fields, |fields| {
+                        fields.set(name, value)
+                    });
+                    self.pop();
+                    self.push(value)?;
+                }
+                Some(op @ (Opcode::GetSuper | Opcode::GetSuperLong)) => {
+                    let name = self.read_string(op);
+                    let superclass = self.peek_class(0)?;
+                    self.pop();
+                    self.bind_method(superclass, name)?;
+                }
                 Some(Opcode::Equal) => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Bool(values_equal(a, b)));
+                    self.push(Value::from(values_equal(a, b)))?;
                 }
                 Some(Opcode::Greater) => {
-                    self.binary_op(|a, b| Value::Bool(a > b))?
+                    self.binary_op(|a, b| Value::from(a > b))?
                 }
                 Some(Opcode::Less) => {
-                    self.binary_op(|a, b| Value::Bool(a < b))?
+                    self.binary_op(|a, b| Value::from(a < b))?
                 }
                 Some(Opcode::Add) => {
                     let b = self.pop();
                     let a = self.pop();
-                    match (a, b) {
-                        (Value::String(a), Value::String(b)) => {
-                            self.push(Value::string(a.into_string() + &b))
+                    match (a.unpack(), b.unpack()) {
+                        (Unpacked::String(a), Unpacked::String(b)) => {
+                            let string = a.as_str().to_owned() + b.as_str();
+                            let string = self.intern(string);
+                            self.push(Value::from(string))?
                         }
-                        (Value::Number(a), Value::Number(b)) => {
-                            self.push(Value::Number(a + b))
+                        (Unpacked::Number(a), Unpacked::Number(b)) => {
+                            self.push(Value::from(a + b))?
                         }
-                        _ => {
-                            self.runtime_error(
+                        (a, _) => {
+                            // point at whichever operand is the odd one out
+                            let operand = match a {
+                                Unpacked::Number(_) | Unpacked::String(_) => 0,
+                                _ => 1,
+                            };
+                            return Err(self.operand_error(
+                                operand,
                                 "Operands must be numbers or strings.",
-                            );
-                            return Err(Error::Runtime);
+                            ));
                         }
                     }
                 }
                 Some(Opcode::Subtract) => {
-                    self.binary_op(|a, b| Value::Number(a - b))?
+                    self.binary_op(|a, b| Value::from(a - b))?
                 }
                 Some(Opcode::Multiply) => {
-                    self.binary_op(|a, b| Value::Number(a * b))?
+                    self.binary_op(|a, b| Value::from(a * b))?
                 }
                 Some(Opcode::Divide) => {
-                    self.binary_op(|a, b| Value::Number(a / b))?
+                    self.binary_op(|a, b| Value::from(a / b))?
                 }
                 Some(Opcode::Negate) => {
                     let value = self.pop();
-                    if let Value::Number(n) = value {
-                        self.push(Value::Number(-n));
+                    if let Unpacked::Number(n) = value.unpack() {
+                        self.push(Value::from(-n))?;
                     } else {
-                        self.runtime_error("Operand must be a number.");
-                        return Err(Error::Runtime);
+                        return Err(
+                            self.operand_error(0, "Operand must be a number.")
+                        );
                     }
                 }
                 Some(Opcode::Not) => {
                     let value = self.pop();
-                    self.push(Value::Bool(is_falsey(&value)));
+                    self.push(Value::from(is_falsey(&value)))?;
                 }
                 Some(Opcode::Print) => {
-                    print_value(&self.pop());
-                    println!();
+                    let value = self.pop();
+                    if let Err(error) = writeln!(self.output, "{value}") {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::Output,
+                            format!("Couldn't write output: {error}."),
+                        ));
+                    }
                 }
                 Some(Opcode::Jump) => {
                     let offset = self.read_short();
-                    self.ip += offset as usize;
+                    self.frame_mut().ip += offset as usize;
                 }
                 Some(Opcode::JumpIfFalse) => {
                     let offset = self.read_short();
                     if is_falsey(self.peek(0)) {
-                        self.ip += offset as usize;
+                        self.frame_mut().ip += offset as usize;
                     }
                 }
                 Some(Opcode::Loop) => {
                     let offset = self.read_short();
-                    self.ip -= offset as usize;
+                    self.frame_mut().ip -= offset as usize;
+                }
+                Some(Opcode::Call) => {
+                    let arg_count = self.read_byte();
+                    let callee = *self.peek(arg_count.into());
+                    self.call_value(callee, arg_count)?;
+                }
+                Some(op @ (Opcode::Invoke | Opcode::InvokeLong)) => {
+                    let method = self.read_string(op);
+                    let arg_count = self.read_byte();
+                    self.invoke(method, arg_count)?;
+                }
+                Some(op @ (Opcode::SuperInvoke | Opcode::SuperInvokeLong)) => {
+                    let method = self.read_string(op);
+                    let arg_count = self.read_byte();
+                    let superclass = self.peek_class(0)?;
+                    self.pop();
+                    self.invoke_from_class(superclass, method, arg_count)?;
+                }
+                Some(op @ (Opcode::Closure | Opcode::ClosureLong)) => {
+                    let Unpacked::Function(function) =
+                        self.read_constant_of(op).unpack()
+                    else {
+                        unreachable!()
+                    };
+                    let mut upvalues =
+                        Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.frame().closure.upvalues[index]
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(ObjClosure { function, upvalues });
+                    self.push(Value::from(closure))?;
+                }
+                Some(Opcode::CloseUpvalue) => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Some(op @ (Opcode::Class | Opcode::ClassLong)) => {
+                    let name = self.read_string(op);
+                    let class = self.alloc(ObjClass {
+                        name,
+                        methods: RefCell::default(),
+                    });
+                    self.push(Value::from(class))?;
+                }
+                Some(Opcode::Inherit) => {
+                    let Unpacked::Class(superclass) = self.peek(1).unpack()
+                    else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Superclass must be a class.",
+                        ));
+                    };
+                    // copy-down inheritance, methods defined later override
+                    let methods: Vec<_> = superclass
+                        .methods
+                        .borrow()
+                        .iter()
+                        .map(|(&name, &method)| (name, method))
+                        .collect();
+                    let subclass = self.peek_class(0)?;
+                    self.grow_table(&subclass.methods, |table| {
+                        table.extend(methods)
+                    });
+                    self.pop();
+                }
+                Some(op @ (Opcode::Method | Opcode::MethodLong)) => {
+                    let name = self.read_string(op);
+                    self.define_method(name)?;
                 }
                 Some(Opcode::Return) => {
-                    return Ok(());
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.len() == base {
+                        return Ok(result);
+                    }
+                    self.push(result)?;
                 }
                 None => {
-                    println!("unknown instruction: {instruction}");
-                    return Err(Error::Runtime);
+                    return Err(self.runtime_error(
+                        RuntimeErrorKind::UnknownInstruction,
+                        format!("Unknown instruction {instruction}."),
+                    ));
                 }
             }
         }
@@ -303,8 +1340,18 @@ fn read_and_inc(value: &mut usize) -> usize {
 }
 
 fn is_falsey(value: &Value) -> bool {
-    match value {
-        Value::Nil | Value::Bool(false) => true,
-        _ => false,
-    }
+    matches!(value.unpack(), Unpacked::Nil | Unpacked::Bool(false))
+}
+
+fn clock_native(
+    _vm: &mut Vm,
+    _args: &[host::Value],
+) -> Result<host::Value, RuntimeError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| {
+        RuntimeError::new(
+            RuntimeErrorKind::Native,
+            "System clock is before epoch.",
+        )
+    })?;
+    Ok(host::Value::from(now.as_secs_f64()))
 }
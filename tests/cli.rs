@@ -0,0 +1,256 @@
+//! Flags and exit codes of the `clox` binary.
+
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+use clox::MAGIC;
+
+fn clox(args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_clox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_ref())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// unique to each test, as they run in parallel
+fn temp_file(name: &str) -> String {
+    let name = format!("clox-{}-{name}", std::process::id());
+    std::env::temp_dir()
+        .join(name)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn evaluates_code_argument() {
+    let output = clox(&["-e", "print 1 + 2;"], "");
+    assert_eq!(stdout(&output), "3\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn reads_script_from_stdin() {
+    let output = clox(&["-"], "print \"from stdin\";");
+    assert_eq!(stdout(&output), "from stdin\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn forwards_remaining_arguments() {
+    let code = "print argc(); print argv(0); print argv(1); print argv(2);";
+    let output = clox(&["-e", code, "first", "--trace"], "");
+    assert_eq!(stdout(&output), "2\nfirst\n--trace\nnil\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn disassembles_without_running() {
+    let output = clox(&["--disassemble", "-e", "print \"ran\";"], "");
+    assert!(stdout(&output).contains("OP_PRINT"));
+    assert!(!stdout(&output).contains("ran\n"));
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn disassembles_loops() {
+    let output = clox(&["--disassemble", "-e", "while (false) {}"], "");
+    assert!(stdout(&output).contains("OP_LOOP             5 -> 0"));
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+#[cfg(feature = "trace")]
+fn traces_to_stderr() {
+    let output = clox(&["--trace", "-e", "print 1;"], "");
+    assert_eq!(stdout(&output), "1\n");
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("OP_PRINT"));
+}
+
+#[test]
+fn exit_codes() {
+    let code = |args: &[&str]| clox(args, "").status.code();
+    assert_eq!(code(&["--unknown"]), Some(64));
+    assert_eq!(code(&["-e"]), Some(64));
+    assert_eq!(code(&["--disassemble"]), Some(64));
+    assert_eq!(code(&["compile"]), Some(64));
+    assert_eq!(code(&["compile", "-"]), Some(64));
+    assert_eq!(code(&["-e", "print 1 +;"]), Some(65));
+    assert_eq!(code(&["-e", "print nil + 1;"]), Some(70));
+    assert_eq!(code(&["tests/lox/does_not_exist.lox"]), Some(74));
+}
+
+#[test]
+fn repl_continues_unbalanced_input_and_exits_on_eof() {
+    let input = "fun add(a, b) {\n  return a + b;\n}\nprint add(1,\n 2);\n";
+    let output = clox(&[], input);
+    assert_eq!(stdout(&output), "> . . > . 3\n> ");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn repl_meta_commands() {
+    let input = "var a = 1;\n:globals\n:time a = 2;\n:dis a + 1\n:reset\n\
+                 print a;\n:nope\n";
+    let output = clox(&[], input);
+    let stdout = stdout(&output);
+    assert!(stdout.contains("a = 1\n"), "{stdout}");
+    assert!(stdout.contains("took "), "{stdout}");
+    assert!(stdout.contains("== <script> =="), "{stdout}");
+    assert!(stdout.contains("OP_GET_GLOBAL"), "{stdout}");
+    assert!(stdout.contains("OP_ADD"), "{stdout}");
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("Undefined variable 'a'"), "{stderr}");
+    assert!(stderr.contains("Unknown command ':nope'"), "{stderr}");
+}
+
+#[test]
+fn repl_prints_lone_expressions() {
+    let input =
+        "1 + 2;\n\"a\" + \"b\"\nvar a = 1;\na = 2;\nprint a; a;\nnil;\n";
+    let output = clox(&[], input);
+    assert_eq!(stdout(&output), "> 3\n> \"ab\"\n> > 2\n> 2\n> > ");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn compiles_to_bytecode_and_runs_it() {
+    let compiled = &temp_file("error.loxc");
+
+    let code = "fun f(a) { print a; return a + nil; }\nf(\"ran\");";
+    let output = clox(&["compile", "-", "-o", compiled], code);
+    assert!(output.status.success());
+    assert!(stdout(&output).is_empty());
+
+    let output = clox(&[compiled], "");
+    assert_eq!(stdout(&output), "ran\n");
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "Operands must be numbers or strings.\n\
+         [line 1] in f()\n\
+         [line 2] in script\n"
+    );
+    assert_eq!(output.status.code(), Some(70));
+
+    let output = clox(&["compile", compiled], "");
+    assert_eq!(output.status.code(), Some(65));
+    std::fs::remove_file(compiled).unwrap();
+}
+
+#[test]
+fn rejects_truncated_bytecode() {
+    let output = clox(&["-"], [MAGIC.as_slice(), &[1]].concat());
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert_eq!(stderr, "Compiled script is truncated.\n");
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn runs_source_starting_like_magic() {
+    let output = clox(&["-e", "loxcount = 1;"], "");
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert!(
+        stderr.contains("Undefined variable 'loxcount'."),
+        "{stderr}"
+    );
+    assert_eq!(output.status.code(), Some(70));
+}
+
+#[test]
+fn rejects_invalid_bytecode() {
+    let compiled = &temp_file("invalid.loxc");
+    let output = clox(&["compile", "-", "-o", compiled], "print 1;");
+    assert!(output.status.success());
+
+    // header, one number constant, then length of the code
+    let code_start = MAGIC.len() + 2 + 4 + 1 + 8 + 4;
+    let mut bytes = std::fs::read(compiled).unwrap();
+    assert_eq!(bytes[code_start + 1], 0);
+    bytes[code_start + 1] = 5;
+    std::fs::write(compiled, bytes).unwrap();
+
+    let output = clox(&[compiled], "");
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "Invalid bytecode at 0000 in script: no constant 5.\n"
+    );
+    assert_eq!(output.status.code(), Some(65));
+    std::fs::remove_file(compiled).unwrap();
+}
+
+#[test]
+fn rejects_deeply_nested_code() {
+    let nested =
+        |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+    let output = clox(&["-"], nested(100_000));
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("Code nested too deeply."), "{stderr}");
+    assert_eq!(stderr.matches("Error").count(), 1, "{stderr}");
+
+    assert_eq!(stdout(&clox(&["-"], nested(150))), "1\n");
+}
+
+#[test]
+fn rejects_jumps_too_long_to_encode() {
+    // three bytes of code each, over 64 KiB in all
+    let body = "print 1;".repeat(25_000);
+    for (source, message) in [
+        (
+            format!("if (true) {{ {body} }}"),
+            "Too much code to jump over.",
+        ),
+        (
+            format!("while (false) {{ {body} }}"),
+            "Loop body too large.",
+        ),
+    ] {
+        let output = clox(&["-"], source);
+        assert_eq!(output.status.code(), Some(65));
+        let stderr = std::str::from_utf8(&output.stderr).unwrap();
+        assert!(stderr.contains(message), "{stderr}");
+        assert_eq!(stderr.matches("Error").count(), 1, "{stderr}");
+    }
+}
+
+#[test]
+fn runs_code_after_256_constants() {
+    let constants: String = (0..300).map(|i| format!("x = {i};")).collect();
+    let code = format!(
+        "var x; {constants}
+        class A {{ hi() {{ return \"A\"; }} }}
+        class B < A {{
+            init() {{ this.n = \"b\"; }}
+            hi() {{ return super.hi() + this.n; }}
+            get() {{ var hi = super.hi; return hi(); }}
+        }}
+        fun f() {{ var b = B(); b.n = \"c\"; return b.hi() + b.get(); }}
+        print f();"
+    );
+    assert_eq!(stdout(&clox(&["-"], &code)), "AcA\n");
+
+    let compiled = &temp_file("constants.loxc");
+    let output = clox(&["compile", "-", "-o", compiled], &code);
+    assert!(output.status.success());
+    assert_eq!(stdout(&clox(&[compiled], "")), "AcA\n");
+    std::fs::remove_file(compiled).unwrap();
+}
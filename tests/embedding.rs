@@ -0,0 +1,251 @@
+//! Api for hosts embedding the [`Vm`].
+
+use std::io::{self, Write};
+
+use clox::{Error, RuntimeError, RuntimeErrorKind, StackSize, Value, Vm};
+
+#[test]
+fn runs_with_smallest_grow_factor() {
+    let mut vm = Vm::default();
+    vm.set_heap_grow_factor(1.0);
+    let source = r#"
+        var s = "";
+        for (var i = 0; i < 1000; i = i + 1) s = s + "x";
+    "#;
+    assert!(vm.interpret(source).is_ok());
+}
+
+#[test]
+#[should_panic(expected = "heap grow factor must be at least 1")]
+fn rejects_shrinking_grow_factor() {
+    Vm::default().set_heap_grow_factor(0.5);
+}
+
+// calls `fails` and returns whether it succeeded
+fn try_fails(vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::from(vm.call_function("fails", &[]).is_ok()))
+}
+
+#[test]
+fn native_can_recover_from_failed_call() {
+    let mut vm = Vm::default();
+    vm.define_native("tryFails", 0, try_fails);
+    let source = r#"
+        fun fails() { return nil + 1; }
+        var succeeded;
+        fun outer() {
+            var local = "kept";
+            succeeded = tryFails();
+            return local;
+        }
+        var result = outer();
+    "#;
+    assert!(vm.interpret(source).is_ok());
+    assert_eq!(vm.get_global("result").unwrap().to_string(), "kept");
+    assert_eq!(vm.get_global("succeeded").unwrap().to_string(), "false");
+}
+
+#[test]
+fn values_outlive_the_vm() {
+    let (string, function) = {
+        let mut vm = Vm::default();
+        vm.interpret("fun f() {}").unwrap();
+        let string = vm.eval_expression(r#""a" + "b""#).unwrap();
+        (string, vm.get_global("f").unwrap())
+    };
+    assert_eq!(string, Value::from("ab"));
+    assert_eq!(function.to_string(), "<fn f>");
+}
+
+#[test]
+fn objects_stay_alive_while_held() {
+    let mut vm = Vm::default();
+    vm.set_heap_grow_factor(1.0);
+    vm.interpret("class Point { init(x) { this.x = x; } }")
+        .unwrap();
+    let point = vm.eval_expression("Point(1)").unwrap();
+    // plenty of collections, while only the host references the instance
+    let source = r#"
+        var s = "";
+        for (var i = 0; i < 1000; i = i + 1) s = s + "x";
+    "#;
+    vm.interpret(source).unwrap();
+    vm.set_global("point", point);
+    assert_eq!(vm.eval_expression("point.x").unwrap(), Value::Number(1.0));
+}
+
+#[test]
+#[should_panic(expected = "object is from another vm")]
+fn rejects_objects_from_another_vm() {
+    let vm = Vm::default();
+    let clock = vm.get_global("clock").unwrap();
+    Vm::default().set_global("clock", clock);
+}
+
+fn is_stack_overflow(result: Result<(), Error>) -> bool {
+    matches!(
+        result,
+        Err(Error::Runtime(error))
+            if error.kind == RuntimeErrorKind::StackOverflow
+    )
+}
+
+#[test]
+fn call_depth_follows_stack_size() {
+    let recurse = "fun f(n) { if (n > 0) f(n - 1); } f(1000);";
+    assert!(is_stack_overflow(Vm::default().interpret(recurse)));
+
+    let size = StackSize::growable(256, 1 << 20);
+    assert!(Vm::with_stack_size(size).interpret(recurse).is_ok());
+
+    let size = StackSize {
+        max_frames: 10,
+        ..StackSize::default()
+    };
+    let mut vm = Vm::with_stack_size(size);
+    assert!(vm
+        .interpret("fun f(n) { if (n > 0) f(n - 1); } f(8);")
+        .is_ok());
+    assert!(is_stack_overflow(vm.interpret("f(9);")));
+}
+
+#[test]
+fn small_stack_still_calls_functions() {
+    let mut vm = Vm::with_stack_size(StackSize::fixed(511));
+    vm.set_output(Vec::<u8>::new());
+    vm.interpret("fun f(n) { if (n > 0) return f(n - 1); return n; }")
+        .unwrap();
+    vm.interpret("print f(10);").unwrap();
+    assert_eq!(vm.take_output::<Vec<u8>>().unwrap(), b"0\n");
+
+    let mut vm = Vm::with_stack_size(StackSize::fixed(16));
+    let recurse = "fun f(n) { if (n > 0) f(n - 1); } f(10);";
+    assert!(is_stack_overflow(vm.interpret(recurse)));
+}
+
+#[test]
+fn captures_print_output() {
+    let mut vm = Vm::default();
+    vm.set_output(Vec::<u8>::new());
+    vm.interpret(r#"print 1 + 2; print "a" + "b";"#).unwrap();
+    let output: Vec<u8> = vm.take_output().unwrap();
+    assert_eq!(output, b"3\nab\n");
+    // back to stdout
+    assert!(vm.take_output::<Vec<u8>>().is_none());
+}
+
+#[test]
+fn take_output_of_other_type_keeps_it() {
+    let mut vm = Vm::default();
+    vm.set_output(Vec::<u8>::new());
+    assert!(vm.take_output::<io::Sink>().is_none());
+    vm.interpret("print 1;").unwrap();
+    assert_eq!(vm.take_output::<Vec<u8>>().unwrap(), b"1\n");
+}
+
+struct Broken;
+
+impl Write for Broken {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("broken"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_print_is_runtime_error() {
+    let mut vm = Vm::default();
+    vm.set_output(Broken);
+    let Err(Error::Runtime(error)) = vm.interpret("print 1;") else {
+        panic!("print to broken output succeeded");
+    };
+    assert_eq!(error.kind, RuntimeErrorKind::Output);
+    assert_eq!(error.message, "Couldn't write output: broken.");
+}
+
+#[test]
+fn globals_round_trip() {
+    let mut vm = Vm::default();
+    vm.set_global("number", Value::from(1.5));
+    vm.set_global("string", Value::from("text"));
+    vm.interpret("var sum = number + 1; var upper = string + \"!\";")
+        .unwrap();
+    assert_eq!(vm.get_global("sum"), Some(Value::Number(2.5)));
+    assert_eq!(vm.get_global("upper"), Some(Value::from("text!")));
+    assert_eq!(vm.get_global("missing"), None);
+}
+
+#[test]
+fn calls_script_functions() {
+    let mut vm = Vm::default();
+    let source = r#"
+        fun greet(name, times) {
+            var s = "";
+            for (var i = 0; i < times; i = i + 1) s = s + name;
+            return s;
+        }
+        class Pair { init(a, b) { this.a = a; this.b = b; } }
+    "#;
+    vm.interpret(source).unwrap();
+    let args = [Value::from("ab"), Value::from(3.0)];
+    assert_eq!(
+        vm.call_function("greet", &args).unwrap(),
+        Value::from("ababab")
+    );
+
+    let pair = vm.call_function("Pair", &args).unwrap();
+    assert_eq!(pair.to_string(), "Pair instance");
+    vm.set_global("pair", pair);
+    assert_eq!(vm.eval_expression("pair.b").unwrap(), Value::Number(3.0));
+
+    let Err(Error::Runtime(error)) = vm.call_function("greet", &[]) else {
+        panic!("call with wrong arity succeeded");
+    };
+    assert_eq!(error.kind, RuntimeErrorKind::WrongArity);
+    let Err(Error::Runtime(error)) = vm.call_function("missing", &[]) else {
+        panic!("call of undefined function succeeded");
+    };
+    assert_eq!(error.kind, RuntimeErrorKind::UndefinedVariable);
+    assert_eq!(error.message, "Undefined variable 'missing'.");
+}
+
+#[test]
+fn evaluates_expressions() {
+    let mut vm = Vm::default();
+    vm.interpret("var a = 2;").unwrap();
+    assert_eq!(vm.eval_expression("a * 3 + 1").unwrap(), Value::Number(7.0));
+    assert_eq!(vm.eval_expression("a == 2").unwrap(), Value::Bool(true));
+    assert_eq!(vm.eval_expression("nil").unwrap(), Value::Nil);
+    assert!(matches!(vm.eval_expression("a +"), Err(Error::Compile(_))));
+    // statements aren't expressions
+    assert!(vm.eval_expression("var b = 1;").is_err());
+}
+
+#[test]
+fn runs_compiled_script_in_any_vm() {
+    let script = clox::compile(
+        r#"
+        fun greeter(greeting) {
+            fun greet(name) { return greeting + ", " + name; }
+            return greet;
+        }
+        class Counter { init() { this.count = 0; } }
+        if (greeter("hi")("you") == "hi, you") count = count + 1;
+    "#,
+    )
+    .unwrap();
+    let mut first = Vm::default();
+    let mut second = Vm::default();
+    for vm in [&mut first, &mut second] {
+        vm.set_global("count", Value::Number(0.0));
+    }
+    first.run(&script).unwrap();
+    first.run(&script).unwrap();
+    second.run(&script).unwrap();
+    assert_eq!(first.get_global("count"), Some(Value::Number(2.0)));
+    assert_eq!(second.get_global("count"), Some(Value::Number(1.0)));
+    assert!(matches!(clox::compile("print ;"), Err(Error::Compile(_))));
+}
@@ -0,0 +1,179 @@
+//! Runs every script under `tests/lox` and checks what it does against
+//! annotations in its comments, same as the Crafting Interpreters suite:
+//!
+//! - `// expect: text` for each line the script prints, in order,
+//! - `// expect runtime error: message` for the runtime error raised on
+//!   that line, which has to be the script's first error,
+//! - `// [line N] Error at 'x': message` for each compile error, or just
+//!   `// Error at 'x': message` for one reported on the comment's own line.
+//!
+//! Any other output, on stdout or stderr, is a failure, except for source
+//! excerpts printed under errors.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    // message and line it's raised on
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expected = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let Some((_, comment)) = line.split_once("// ") else {
+                continue;
+            };
+            if let Some(text) = comment.strip_prefix("expect: ") {
+                expected.output.push(text.to_string());
+            } else if let Some(message) =
+                comment.strip_prefix("expect runtime error: ")
+            {
+                expected.runtime_error =
+                    Some((message.to_string(), line_number));
+            } else if comment.starts_with("[line ") {
+                expected.compile_errors.push(comment.to_string());
+            } else if comment.starts_with("Error") {
+                expected
+                    .compile_errors
+                    .push(format!("[line {line_number}] {comment}"));
+            }
+        }
+        expected
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            EXIT_COMPILE_ERROR
+        } else if self.runtime_error.is_some() {
+            EXIT_RUNTIME_ERROR
+        } else {
+            0
+        }
+    }
+}
+
+// lines like ` 12 | print a;` and `    |       ^`
+fn is_excerpt(line: &str) -> bool {
+    line.trim_start()
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .trim_start()
+        .starts_with('|')
+}
+
+// `[line 3]` at the start of `line`, as in compile errors and stack traces
+fn line_number(line: &str) -> Option<usize> {
+    let rest = line.strip_prefix("[line ")?;
+    let (number, _) = rest.split_once(']')?;
+    number.parse().ok()
+}
+
+fn check(path: &Path) -> Vec<String> {
+    let source = fs::read_to_string(path).unwrap();
+    let expected = Expectations::parse(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_clox"))
+        .arg(path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut failures = vec![];
+
+    let mut stderr_lines = stderr.lines().filter(|line| !is_excerpt(line));
+    if let Some((message, line)) = &expected.runtime_error {
+        match stderr_lines.next() {
+            Some(actual) if actual == message => {}
+            actual => failures.push(format!(
+                "Expected runtime error '{message}', got {actual:?}."
+            )),
+        }
+        match stderr_lines.next().and_then(line_number) {
+            Some(actual) if actual == *line => {}
+            actual => failures.push(format!(
+                "Expected runtime error on line {line}, got {actual:?}."
+            )),
+        }
+        // rest is the stack trace
+        stderr_lines.by_ref().for_each(drop);
+    }
+    let mut compile_errors = expected.compile_errors.iter();
+    for actual in stderr_lines {
+        match compile_errors.next() {
+            Some(error) if error == actual => {}
+            Some(error) => failures
+                .push(format!("Expected error '{error}', got '{actual}'.")),
+            None => failures.push(format!("Unexpected error '{actual}'.")),
+        }
+    }
+    for error in compile_errors {
+        failures.push(format!("Missing expected error '{error}'."));
+    }
+
+    let mut stdout_lines = stdout.lines();
+    for text in &expected.output {
+        match stdout_lines.next() {
+            Some(actual) if actual == text => {}
+            Some(actual) => failures
+                .push(format!("Expected output '{text}', got '{actual}'.")),
+            None => failures.push(format!("Missing expected output '{text}'.")),
+        }
+    }
+    for actual in stdout_lines {
+        failures.push(format!("Unexpected output '{actual}'."));
+    }
+
+    let exit_code = output.status.code();
+    if exit_code != Some(expected.exit_code()) {
+        failures.push(format!(
+            "Expected exit code {}, got {exit_code:?}.",
+            expected.exit_code()
+        ));
+    }
+    failures
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+#[test]
+fn golden_files() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let mut scripts = vec![];
+    collect_scripts(&root, &mut scripts);
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts in {}", root.display());
+
+    let mut failed = 0;
+    for script in &scripts {
+        let failures = check(script);
+        if failures.is_empty() {
+            continue;
+        }
+        failed += 1;
+        let name = script.strip_prefix(&root).unwrap_or(script);
+        eprintln!("FAIL {}", name.display());
+        for failure in failures {
+            eprintln!("     {failure}");
+        }
+    }
+    assert!(failed == 0, "{failed} of {} scripts failed", scripts.len());
+}